@@ -0,0 +1,256 @@
+//! Append-only log of who changed what, for compliance.
+//!
+//! Every handler that changes a token, user, org or api key records an entry
+//! in the same transaction as the change, or in the script making it when the
+//! change may not be made. Entries are appended to the `mpix.audit` list and never
+//! rewritten or trimmed, so an entry's position in the list is its id.
+use crate::error::Result;
+use crate::metrics::RedisConnection;
+use crate::{timezone, Auth, RemoteAddr};
+use {
+    futures::compat::Future01CompatExt,
+    hyper::{Body, Request},
+    serde::{Deserialize, Serialize},
+};
+
+/// List of every audit entry, oldest first
+pub const AUDIT_LOG: &str = "mpix.audit";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    #[serde(rename = "token.create")]
+    TokenCreate,
+    #[serde(rename = "token.archive")]
+    TokenArchive,
+    #[serde(rename = "token.unarchive")]
+    TokenUnarchive,
+    #[serde(rename = "token.access.grant")]
+    AccessGrant,
+    #[serde(rename = "token.access.revoke")]
+    AccessRevoke,
+    #[serde(rename = "token.share.create")]
+    ShareCreate,
+    #[serde(rename = "token.share.revoke")]
+    ShareRevoke,
+    #[serde(rename = "user.create")]
+    UserCreate,
+    #[serde(rename = "user.delete")]
+    UserDelete,
+    #[serde(rename = "user.disable")]
+    UserDisable,
+    #[serde(rename = "user.enable")]
+    UserEnable,
+    #[serde(rename = "user.password")]
+    UserPassword,
+    #[serde(rename = "user.quota")]
+    UserQuota,
+    #[serde(rename = "key.issue")]
+    KeyIssue,
+    #[serde(rename = "key.revoke")]
+    KeyRevoke,
+    #[serde(rename = "org.create")]
+    OrgCreate,
+    #[serde(rename = "org.member.set")]
+    OrgMemberSet,
+    #[serde(rename = "org.member.remove")]
+    OrgMemberRemove,
+    #[serde(rename = "org.quota")]
+    OrgQuota,
+    #[serde(rename = "session.login")]
+    SessionLogin,
+    #[serde(rename = "session.logout")]
+    SessionLogout,
+}
+
+/// Who made a change. Taken from a request before handlers read its body.
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub name: String,
+    pub key_id: Option<String>,
+    pub ip: Option<String>,
+    pub forwarded_for: Option<String>,
+}
+impl Actor {
+    pub fn new(auth: &Auth, request: &Request<Body>) -> Self {
+        Self::anonymous(&auth.user_name, request).with_key(auth.key_id.clone())
+    }
+
+    /// Someone that isn't signed in yet, e.g. while signing in
+    pub fn anonymous(name: &str, request: &Request<Body>) -> Self {
+        Self {
+            name: name.to_string(),
+            key_id: None,
+            ip: peer_ip(request),
+            forwarded_for: crate::handlers::forwarded_ip(request),
+        }
+    }
+
+    fn with_key(mut self, key_id: Option<String>) -> Self {
+        self.key_id = key_id;
+        self
+    }
+}
+
+/// Address of the peer a request came in on. Forwarded addresses are set by
+/// whoever sends the request, so they're only kept next to it.
+fn peer_ip(request: &Request<Body>) -> Option<String> {
+    request
+        .extensions()
+        .get::<RemoteAddr>()
+        .map(|RemoteAddr(addr)| addr.ip().to_string())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    /// Name of the user that made the change
+    pub actor: String,
+    /// Prefix of the api key they made it with, changes made with a session
+    /// or the admin credential have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    pub action: Action,
+    /// What was changed, e.g. a token or `user/key name`
+    pub target: String,
+    /// Address of the peer the change came in on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Client address claimed by the `x-forwarded-for` header, unverified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_for: Option<String>,
+    #[serde(serialize_with = "timezone::serialize")]
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+impl Entry {
+    pub fn new(actor: &Actor, action: Action, target: &str) -> Self {
+        Self {
+            actor: actor.name.clone(),
+            key_id: actor.key_id.clone(),
+            action,
+            target: target.to_string(),
+            ip: actor.ip.clone(),
+            forwarded_for: actor.forwarded_for.clone(),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+}
+impl redis::FromRedisValue for Entry {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Entry> {
+        match *v {
+            redis::Value::Data(ref bytes) => Ok(serde_json::from_slice(bytes).map_err(|_| {
+                (
+                    redis::ErrorKind::TypeError,
+                    "Invalid audit entry json bytes",
+                )
+            })?),
+            _ => Err((
+                redis::ErrorKind::TypeError,
+                "Response type not audit entry compatible.",
+            ))?,
+        }
+    }
+}
+
+/// Record that `actor` made the change `action` to `target` in `pipe`, which
+/// makes the change in the same transaction
+pub fn record(
+    pipe: &mut redis::Pipeline,
+    actor: &Actor,
+    action: Action,
+    target: &str,
+) -> Result<()> {
+    pipe.cmd("RPUSH")
+        .arg(AUDIT_LOG)
+        .arg(entry(actor, action, target)?)
+        .ignore();
+    Ok(())
+}
+
+/// Json of the entry recording that `actor` made the change `action` to
+/// `target`, for scripts that record it once they've made the change
+pub fn entry(actor: &Actor, action: Action, target: &str) -> Result<String> {
+    Ok(serde_json::to_string(&Entry::new(actor, action, target))?)
+}
+
+/// Ids of the page of at most `limit` entries before entry `before`, newest
+/// first, as an inclusive `LRANGE` range. `None` when there's nothing before.
+fn page_range(len: u64, before: Option<u64>, limit: u64) -> Option<(u64, u64)> {
+    let end = before.unwrap_or(len).min(len);
+    if end == 0 || limit == 0 {
+        return None;
+    }
+    Some((end.saturating_sub(limit), end - 1))
+}
+
+/// A page of at most `limit` entries before entry `before`, newest first,
+/// with their ids
+pub async fn page(
+    conn: RedisConnection,
+    before: Option<u64>,
+    limit: u64,
+) -> Result<(RedisConnection, Vec<(u64, Entry)>)> {
+    let (conn, len): (_, u64) = redis::cmd("LLEN")
+        .arg(AUDIT_LOG)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let (start, stop) = match page_range(len, before, limit) {
+        Some(range) => range,
+        None => return Ok((conn, vec![])),
+    };
+    let (conn, entries): (_, Vec<Entry>) = redis::cmd("LRANGE")
+        .arg(AUDIT_LOG)
+        .arg(start)
+        .arg(stop)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let mut page: Vec<_> = (start..).zip(entries).collect();
+    page.reverse();
+    Ok((conn, page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_walk_back_from_the_newest_entry() {
+        assert_eq!(page_range(0, None, 10), None);
+        assert_eq!(page_range(25, None, 10), Some((15, 24)));
+        assert_eq!(page_range(25, Some(15), 10), Some((5, 14)));
+        assert_eq!(page_range(25, Some(5), 10), Some((0, 4)));
+        assert_eq!(page_range(25, Some(0), 10), None);
+        // cursors past the end start from the newest entry
+        assert_eq!(page_range(25, Some(100), 10), Some((15, 24)));
+    }
+
+    #[test]
+    fn actors_are_the_peer_not_who_they_claim_to_be() {
+        let mut request = Request::builder()
+            .header("x-forwarded-for", "203.0.113.7, 10.0.0.2")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(RemoteAddr("10.0.0.5:4000".parse().unwrap()));
+        let actor = Actor::anonymous("jane", &request);
+        assert_eq!(actor.ip.as_deref(), Some("10.0.0.5"));
+        assert_eq!(actor.forwarded_for.as_deref(), Some("203.0.113.7"));
+    }
+
+    #[test]
+    fn entries_name_their_action() {
+        let actor = Actor {
+            name: "jane".into(),
+            key_id: Some("abcd1234".into()),
+            ip: Some("10.0.0.1".into()),
+            forwarded_for: None,
+        };
+        let entry = Entry::new(&actor, Action::AccessGrant, "tok");
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["action"], "token.access.grant");
+        assert_eq!(json["actor"], "jane");
+        assert_eq!(json["key_id"], "abcd1234");
+        assert_eq!(json["target"], "tok");
+    }
+}
@@ -0,0 +1,394 @@
+//! In-process cache of checked api keys, so requests don't need a redis
+//! round trip to authenticate. Keys are cached by digest, along with the org
+//! the request asked to act in, for at most `TTL` and never past their
+//! expiry, and the cache holds at most `CAPACITY` of them.
+//!
+//! Changes that would turn a cached key away, revoking it or disabling,
+//! deleting or changing the orgs of its user, are published on
+//! `mpix.auth_invalidate` and every instance drops the affected entries as
+//! soon as it hears of them. Quiet subscriptions are pinged every
+//! `PING_INTERVAL` to notice connections that died without a word, and
+//! instances that lose their subscription drop everything and subscribe
+//! again, as they may have missed changes. Keys checked while an invalidation arrives
+//! aren't cached, see `generation`. When a key was last used is only recorded
+//! when it's checked against redis, so it can lag by up to `TTL`.
+use crate::configuration::CONFIG;
+use crate::error::Result;
+use crate::metrics::{self, RedisConnection};
+use crate::{crypto, Auth};
+use {
+    futures::compat::Future01CompatExt,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+        time::{Duration, Instant},
+    },
+};
+
+lazy_static::lazy_static! {
+    pub static ref LOG: slog::Logger = crate::LOG.new(slog::o!("mod" => "auth_cache"));
+    static ref CACHE: AuthCache = AuthCache::new(CAPACITY);
+}
+
+/// Channel invalidations are published on
+pub const INVALIDATIONS: &str = "mpix.auth_invalidate";
+
+/// Longest a checked key is trusted without checking it again
+const TTL: Duration = Duration::from_secs(30);
+
+/// Most keys held at once
+const CAPACITY: usize = 10_000;
+
+/// How long to wait before subscribing again after losing the subscription
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// How long the subscription can stay quiet before it's pinged, and how long
+/// the ping has to be answered before the subscription is given up on
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Cached credentials that are no longer valid
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Invalidation {
+    /// Every entry of the api key with this prefix
+    Key(String),
+    /// Every entry of the user with this name
+    User(String),
+}
+
+struct Entry {
+    auth: Auth,
+    expires: Instant,
+}
+
+struct AuthCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    capacity: usize,
+    /// Bumped by every invalidation, under the entries' lock
+    generation: AtomicU64,
+}
+impl AuthCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        // entries are replaced whole, so a panic can't leave one half written
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn get(&self, key: &str, now: Instant) -> Option<Auth> {
+        let mut entries = self.entries();
+        match entries.get(key) {
+            Some(entry) if entry.expires > now => Some(entry.auth.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache `auth`, checked at `generation`, under `key` until `expires`.
+    /// Nothing is cached if invalidations arrived since the check, as they may
+    /// have been about `auth`. A full cache first drops expired entries and
+    /// then, if it's still full, the one expiring soonest.
+    fn insert(&self, key: String, auth: Auth, generation: u64, expires: Instant, now: Instant) {
+        let mut entries = self.entries();
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.capacity {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+        entries.insert(key, Entry { auth, expires });
+    }
+
+    fn invalidate(&self, invalidation: &Invalidation) {
+        let mut entries = self.entries();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.retain(|_, entry| match invalidation {
+            Invalidation::Key(prefix) => entry.auth.key_id.as_ref() != Some(prefix),
+            Invalidation::User(name) => entry.auth.user_name != *name,
+        });
+    }
+
+    fn clear(&self) {
+        let mut entries = self.entries();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.clear();
+    }
+}
+
+/// What credentials are cached under, api keys themselves are never kept
+fn cache_key(api_key: &str, requested_org: Option<&str>) -> String {
+    format!(
+        "{}:{}",
+        crypto::digest(api_key),
+        requested_org.unwrap_or("")
+    )
+}
+
+/// Credentials cached for `api_key` acting in `requested_org`
+pub fn lookup(api_key: &str, requested_org: Option<&str>) -> Option<Auth> {
+    let found = CACHE.get(&cache_key(api_key, requested_org), Instant::now());
+    match found {
+        Some(_) => metrics::AUTH_CACHE_HITS.inc(),
+        None => metrics::AUTH_CACHE_MISSES.inc(),
+    }
+    found
+}
+
+/// How many times cached credentials were invalidated so far. Read before
+/// checking a key and handed to `store`, so credentials that changed while
+/// they were checked aren't cached.
+pub fn generation() -> u64 {
+    CACHE.generation.load(Ordering::SeqCst)
+}
+
+/// Cache the credentials `api_key` was checked to have, starting at
+/// `generation`, until the key expires at the latest
+pub fn store(
+    api_key: &str,
+    requested_org: Option<&str>,
+    auth: &Auth,
+    key_expires: Option<chrono::DateTime<chrono::Utc>>,
+    generation: u64,
+) {
+    let ttl = match key_expires {
+        Some(expires) => match (expires - chrono::Utc::now()).to_std() {
+            Ok(left) => left.min(TTL),
+            Err(_) => return,
+        },
+        None => TTL,
+    };
+    let now = Instant::now();
+    CACHE.insert(
+        cache_key(api_key, requested_org),
+        auth.clone(),
+        generation,
+        now + ttl,
+        now,
+    );
+}
+
+/// Tell every instance to drop the cached credentials `invalidation` names
+pub async fn publish(
+    conn: RedisConnection,
+    invalidation: &Invalidation,
+) -> Result<RedisConnection> {
+    CACHE.invalidate(invalidation);
+    let (conn, _): (_, u64) = redis::cmd("PUBLISH")
+        .arg(INVALIDATIONS)
+        .arg(serde_json::to_string(invalidation)?)
+        .query_async(conn)
+        .compat()
+        .await?;
+    Ok(conn)
+}
+
+/// The payload of a reply on the subscription connection, if it's a message
+/// rather than an answer to `SUBSCRIBE` or `PING`
+fn payload(reply: &redis::Value) -> redis::RedisResult<Option<String>> {
+    let reply: Vec<redis::Value> = redis::from_redis_value(reply)?;
+    match reply.as_slice() {
+        [kind, _, payload] if redis::from_redis_value::<String>(kind)? == "message" => {
+            Ok(Some(redis::from_redis_value(payload)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Listen for invalidations until the subscription is lost
+fn listen() -> redis::RedisResult<()> {
+    let client = redis::Client::open(CONFIG.redis_url.as_ref())?;
+    let mut conn = client.get_connection()?;
+    conn.set_read_timeout(Some(PING_INTERVAL))?;
+    conn.send_packed_command(
+        &redis::cmd("SUBSCRIBE")
+            .arg(INVALIDATIONS)
+            .get_packed_command(),
+    )?;
+    conn.recv_response()?;
+    // anything published before the subscription started was missed
+    CACHE.clear();
+    slog::info!(LOG, "listening for auth invalidations");
+    let mut pinged = false;
+    loop {
+        let reply = match conn.recv_response() {
+            Ok(reply) => reply,
+            Err(e) if e.is_timeout() && !pinged => {
+                conn.send_packed_command(&redis::cmd("PING").get_packed_command())?;
+                pinged = true;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        pinged = false;
+        let payload = match payload(&reply)? {
+            Some(payload) => payload,
+            None => continue,
+        };
+        match serde_json::from_str::<Invalidation>(&payload) {
+            Ok(invalidation) => CACHE.invalidate(&invalidation),
+            Err(e) => {
+                slog::warn!(LOG, "ignoring invalid auth invalidation";
+                            "payload" => payload, "error" => format!("{}", e))
+            }
+        }
+    }
+}
+
+/// Start listening for invalidations published by every instance, on a
+/// thread of its own since subscriptions block their connection
+pub fn subscribe() {
+    std::thread::Builder::new()
+        .name("auth-invalidations".into())
+        .spawn(|| loop {
+            if let Err(e) = listen() {
+                slog::error!(LOG, "lost auth invalidation subscription"; "error" => format!("{}", e));
+            }
+            CACHE.clear();
+            std::thread::sleep(RESUBSCRIBE_DELAY);
+        })
+        .expect("unable to spawn auth invalidation listener");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(name: &str, key_id: &str) -> Auth {
+        let mut auth = Auth::admin();
+        auth.user_name = name.into();
+        auth.key_id = Some(key_id.into());
+        auth
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = AuthCache::new(10);
+        let now = Instant::now();
+        cache.insert("k".into(), auth("jane", "abcd1234"), 0, now + TTL, now);
+        assert!(cache.get("k", now).is_some());
+        assert!(cache.get("k", now + TTL).is_none());
+        assert!(cache.entries().is_empty());
+    }
+
+    #[test]
+    fn full_caches_drop_the_entry_expiring_soonest() {
+        let cache = AuthCache::new(2);
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        cache.insert("a".into(), auth("a", "aaaaaaaa"), 0, now + secs(20), now);
+        cache.insert("b".into(), auth("b", "bbbbbbbb"), 0, now + secs(10), now);
+        cache.insert("c".into(), auth("c", "cccccccc"), 0, now + secs(30), now);
+        assert!(cache.get("a", now).is_some());
+        assert!(cache.get("b", now).is_none());
+        assert!(cache.get("c", now).is_some());
+    }
+
+    #[test]
+    fn invalidations_drop_keys_and_users() {
+        let cache = AuthCache::new(10);
+        let now = Instant::now();
+        cache.insert("k1".into(), auth("jane", "abcd1234"), 0, now + TTL, now);
+        cache.insert("k2".into(), auth("jane", "efgh5678"), 0, now + TTL, now);
+        cache.insert("k3".into(), auth("joe", "ijkl9012"), 0, now + TTL, now);
+
+        cache.invalidate(&Invalidation::Key("abcd1234".into()));
+        assert!(cache.get("k1", now).is_none());
+        assert!(cache.get("k2", now).is_some());
+
+        cache.invalidate(&Invalidation::User("jane".into()));
+        assert!(cache.get("k2", now).is_none());
+        assert!(cache.get("k3", now).is_some());
+    }
+
+    #[test]
+    fn keys_checked_during_an_invalidation_arent_cached() {
+        let cache = AuthCache::new(10);
+        let now = Instant::now();
+        let generation = cache.generation.load(Ordering::SeqCst);
+        // jane's key is being checked against redis when she's disabled
+        cache.invalidate(&Invalidation::User("jane".into()));
+        cache.insert(
+            "k".into(),
+            auth("jane", "abcd1234"),
+            generation,
+            now + TTL,
+            now,
+        );
+        assert!(cache.get("k", now).is_none());
+
+        let generation = cache.generation.load(Ordering::SeqCst);
+        cache.insert(
+            "k".into(),
+            auth("jane", "abcd1234"),
+            generation,
+            now + TTL,
+            now,
+        );
+        assert!(cache.get("k", now).is_some());
+        cache.clear();
+        assert_ne!(cache.generation.load(Ordering::SeqCst), generation);
+    }
+
+    #[test]
+    fn only_messages_carry_invalidations() {
+        let data = |s: &str| redis::Value::Data(s.as_bytes().to_vec());
+        let message = redis::Value::Bulk(vec![
+            data("message"),
+            data(INVALIDATIONS),
+            data(r#"{"user":"jane"}"#),
+        ]);
+        assert_eq!(
+            payload(&message).unwrap().as_deref(),
+            Some(r#"{"user":"jane"}"#)
+        );
+        // answers to keepalive pings
+        let pong = redis::Value::Bulk(vec![data("pong"), data("")]);
+        assert_eq!(payload(&pong).unwrap(), None);
+        let subscribed = redis::Value::Bulk(vec![
+            data("subscribe"),
+            data(INVALIDATIONS),
+            redis::Value::Int(1),
+        ]);
+        assert_eq!(payload(&subscribed).unwrap(), None);
+    }
+
+    #[test]
+    fn invalidations_are_published_as_json() {
+        let json = serde_json::to_string(&Invalidation::Key("abcd1234".into())).unwrap();
+        assert_eq!(json, r#"{"key":"abcd1234"}"#);
+        assert_eq!(
+            serde_json::from_str::<Invalidation>(r#"{"user":"jane"}"#).unwrap(),
+            Invalidation::User("jane".into())
+        );
+        // api keys are cached by digest
+        assert!(!cache_key("secret-api-key-value", Some("acme")).contains("secret"));
+        assert_ne!(
+            cache_key("secret-api-key-value", Some("acme")),
+            cache_key("secret-api-key-value", None)
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::Environment;
-use std::env;
+use std::{env, net::IpAddr};
 
 lazy_static::lazy_static! {
     pub static ref LOG: slog::Logger = { crate::LOG.new(slog::o!("mod" => "configuration")) };
@@ -10,11 +10,19 @@ pub struct Config {
     pub env: Environment,
     pub redis_url: String,
     pub auth_token: String,
+    pub share_secret: String,
+    /// Secret api keys are hashed with, changing it invalidates every key
+    pub key_secret: String,
+    pub metrics_token: Option<String>,
+    /// Addresses that can read metrics without the token, none unless set.
+    /// Behind a proxy every client has the proxy's address, so only opt in
+    /// when clients reach the server directly.
+    pub metrics_allowlist: Vec<IpAddr>,
 }
 impl Config {
     pub fn load() -> Self {
         let redis_host = env::var("REDIS_HOST").expect("missing var: redis_host");
-        let redis_pass= env::var("REDIS_PASSWORD").expect("missing var: redis_password");
+        let redis_pass = env::var("REDIS_PASSWORD").expect("missing var: redis_password");
         let redis_url = format!("redis://:{}@{}", redis_pass, redis_host);
         Self {
             env: env::var("ENV")
@@ -23,6 +31,16 @@ impl Config {
                 .expect("invalid env"),
             redis_url: redis_url,
             auth_token: env::var("AUTH_TOKEN").expect("missing var: auth_token"),
+            share_secret: env::var("SHARE_SECRET").expect("missing var: share_secret"),
+            key_secret: env::var("API_KEY_SECRET").expect("missing var: api_key_secret"),
+            metrics_token: env::var("METRICS_TOKEN").ok().filter(|t| !t.is_empty()),
+            metrics_allowlist: env::var("METRICS_ALLOWLIST")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| ip.parse().expect("invalid metrics allowlist address"))
+                .collect(),
         }
     }
 }
@@ -0,0 +1,149 @@
+use crate::error::Result;
+use {
+    futures::{channel::oneshot, executor::ThreadPool},
+    hmac::{Hmac, Mac},
+    sha2::{Digest, Sha256},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Threads passwords are hashed and checked on
+const PASSWORD_THREADS: usize = 2;
+
+lazy_static::lazy_static! {
+    /// Argon2 is slow and memory hungry by design, so it runs on threads of
+    /// its own rather than holding up the ones serving requests
+    static ref PASSWORD_POOL: ThreadPool = ThreadPool::builder()
+        .pool_size(PASSWORD_THREADS)
+        .name_prefix("password-")
+        .create()
+        .expect("unable to start password threads");
+
+    /// Hash checked for users without a password
+    static ref UNSET_PASSWORD: String =
+        hash_now(&uuid::Uuid::new_v4().to_string()).expect("unable to hash password");
+}
+
+fn mac(secret: &str, message: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// Sign `message` with `secret`, returning a url safe signature
+pub fn sign(secret: &str, message: &str) -> String {
+    let sig = mac(secret, message).finalize().into_bytes();
+    base64::encode_config(&sig, base64::URL_SAFE_NO_PAD)
+}
+
+/// Check, in constant time, that `signature` was produced by `sign(secret, message)`
+pub fn verify(secret: &str, message: &str, signature: &str) -> bool {
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(sig) => mac(secret, message).verify_slice(&sig).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Url safe sha256 digest of `message`
+pub fn digest(message: &str) -> String {
+    base64::encode_config(&Sha256::digest(message.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Compare two secrets without leaking how much of them matched
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+fn hash_now(password: &str) -> Result<String> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: 19 * 1024,
+        time_cost: 2,
+        ..argon2::Config::default()
+    };
+    let salt = uuid::Uuid::new_v4();
+    Ok(
+        argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &config)
+            .map_err(|e| format!("error hashing password {:?}", e))?,
+    )
+}
+
+/// Run `f` on the password threads
+async fn on_password_pool<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    PASSWORD_POOL.spawn_ok(async move {
+        let _ = tx.send(f());
+    });
+    Ok(rx.await.map_err(|_| "password thread dropped its result")?)
+}
+
+/// Hash a password for storage with argon2id and a random salt
+pub async fn hash_password(password: String) -> Result<String> {
+    on_password_pool(move || hash_now(&password)).await?
+}
+
+/// Check `password` against a hash from `hash_password`. Without a hash the
+/// password is turned away, after taking as long as a wrong one would.
+pub async fn verify_password(hash: Option<String>, password: String) -> Result<bool> {
+    on_password_pool(move || match hash {
+        Some(hash) => argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false),
+        None => {
+            let _ = argon2::verify_encoded(&UNSET_PASSWORD, password.as_bytes());
+            false
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_round_trips() {
+        let sig = sign("secret", "share.1700000000");
+        assert!(verify("secret", "share.1700000000", &sig));
+    }
+
+    #[test]
+    fn tampered_message_is_rejected() {
+        let sig = sign("secret", "share.1700000000");
+        assert!(!verify("secret", "share.1900000000", &sig));
+        assert!(!verify("other-secret", "share.1700000000", &sig));
+        assert!(!verify("secret", "share.1700000000", "not-a-signature"));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_values() {
+        assert!(constant_time_eq("token", "token"));
+        assert!(!constant_time_eq("token", "tokem"));
+        assert!(!constant_time_eq("token", "token-and-more"));
+        assert!(!constant_time_eq("", "token"));
+    }
+
+    #[test]
+    fn passwords_are_salted() {
+        futures::executor::block_on(async {
+            let verify = |hash: Option<&str>, password: &str| {
+                verify_password(hash.map(String::from), password.to_string())
+            };
+            let hash = hash_password("correct horse".into()).await.unwrap();
+            assert!(!hash.contains("correct horse"));
+            assert!(verify(Some(&hash), "correct horse").await.unwrap());
+            assert!(!verify(Some(&hash), "battery staple").await.unwrap());
+            assert_ne!(hash, hash_password("correct horse".into()).await.unwrap());
+            assert!(!verify(Some("not-a-hash"), "correct horse").await.unwrap());
+            // users without a password can't sign in with any
+            assert!(!verify(None, "").await.unwrap());
+        });
+    }
+}
@@ -24,6 +24,23 @@ impl Error {
             false
         }
     }
+
+    /// The http status that should be returned to the client for this error
+    pub fn status(&self) -> hyper::StatusCode {
+        use self::ErrorKind::*;
+        use hyper::StatusCode;
+        match *self.kind() {
+            MissingAuth(_) | InvalidAuth(_) => StatusCode::UNAUTHORIZED,
+            BadRequest(_) => StatusCode::BAD_REQUEST,
+            DoesNotExist(_) => StatusCode::NOT_FOUND,
+            Forbidden(_) => StatusCode::FORBIDDEN,
+            Conflict(_) => StatusCode::CONFLICT,
+            TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Redis(ref e) if e.is_io_error() => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -32,9 +49,14 @@ impl std::fmt::Display for Error {
         match *self.kind() {
             S(ref s) => write!(f, "{}", s),
             Internal(ref s) => write!(f, "InternalError: {}", s),
+            MissingAuth(ref s) => write!(f, "MissingAuth: {}", s),
             InvalidAuth(ref s) => write!(f, "InvalidAuth: {}", s),
             BadRequest(ref s) => write!(f, "BadRequest: {}", s),
             DoesNotExist(ref s) => write!(f, "DoesNotExist: {}", s),
+            Forbidden(ref s) => write!(f, "Forbidden: {}", s),
+            Conflict(ref s) => write!(f, "Conflict: {}", s),
+            TooManyRequests(ref s) => write!(f, "TooManyRequests: {}", s),
+            MethodNotAllowed(ref s) => write!(f, "MethodNotAllowed: {}", s),
             MissingUriParam(ref s) => write!(f, "MissingUriParam: {}", s),
             InvalidUriParam(ref s) => write!(f, "InvalidUriParam: {}", s),
 
@@ -47,6 +69,7 @@ impl std::fmt::Display for Error {
             HeaderInvalidValue(ref e) => write!(f, "HeaderInvalidValue: {}", e),
             Redis(ref e) => write!(f, "RedisError: {}", e),
             Json(ref e) => write!(f, "JsonError: {}", e),
+            Csv(ref e) => write!(f, "CsvError: {}", e),
         }
     }
 }
@@ -66,6 +89,7 @@ impl std::error::Error for Error {
             ParseBool(ref e) => e,
             Redis(ref e) => e,
             Json(ref e) => e,
+            Csv(ref e) => e,
             _ => return None,
         })
     }
@@ -75,9 +99,14 @@ impl std::error::Error for Error {
 pub enum ErrorKind {
     S(String),
     Internal(String),
+    MissingAuth(String),
     InvalidAuth(String),
     BadRequest(String),
     DoesNotExist(String),
+    Forbidden(String),
+    Conflict(String),
+    TooManyRequests(String),
+    MethodNotAllowed(String),
     MissingUriParam(String),
     InvalidUriParam(String),
 
@@ -90,6 +119,7 @@ pub enum ErrorKind {
     HeaderInvalidValue(http::header::InvalidHeaderValue),
     Redis(redis::RedisError),
     Json(serde_json::error::Error),
+    Csv(csv::Error),
 }
 
 impl From<ErrorKind> for Error {
@@ -185,3 +215,11 @@ impl From<serde_json::Error> for Error {
         }
     }
 }
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Error {
+        Error {
+            kind: Box::new(ErrorKind::Csv(e)),
+        }
+    }
+}
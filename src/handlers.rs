@@ -1,51 +1,99 @@
-use crate::error::Result;
-use crate::Context;
+use crate::audit::{self, Action, Actor};
+use crate::configuration::CONFIG;
+use crate::error::{Error, ErrorKind, Result};
+use crate::metrics::{self, RedisConnection};
+use crate::orgs::Role;
+use crate::service::ETagSource;
+use crate::{crypto, quotas, timezone, Auth, Context, TOKEN_REGISTRY};
 use {
+    chrono_tz::Tz,
+    futures::StreamExt,
     futures_util::{
         compat::{Future01CompatExt, Stream01CompatExt},
         TryStreamExt,
     },
-    hyper::{Body, Response, StatusCode},
+    hyper::{header::HeaderValue, Body, Method, Request, Response, StatusCode},
     serde::{Deserialize, Serialize},
+    std::collections::{BTreeMap, HashMap, HashSet},
 };
 
+pub mod admin;
+pub mod dashboard;
+pub mod orgs;
+
 lazy_static::lazy_static! {
     pub static ref LOG: slog::Logger = { crate::LOG.new(slog::o!("mod" => "handlers")) };
 }
 
-static INDEX: &'static str = r##"
-<html>
-    <head>
-        <link rel="shortcut icon" href="/p/favicon?v=1" type="image/png">
-    </head>
-    hello! <img src="/p/163e71e1a222461fac0a139dddacf1d5"/>
-</html>
-"##;
+/// Index of the newest event kept for each token, older events are dropped
+const MAX_TOKEN_EVENTS: isize = 200;
 
-pub async fn index(_ctx: Context) -> Result<Response<Body>> {
-    let resp = Response::builder()
-        .header("content-type", "text/html")
-        .body(Body::from(INDEX))?;
-    Ok(resp)
-}
+/// Global set of archived tokens, which no longer record events
+static ARCHIVED_TOKENS: &str = "mpix.archived_tokens";
+
+/// Longest vanity token a user can request
+const MAX_TOKEN_LEN: usize = 64;
+
+/// Vanity tokens that would be shadowed by other `/stat/...` routes
+const RESERVED_TOKENS: &[&str] = &["export", "batch"];
 
 #[derive(Serialize, Deserialize)]
 struct Token {
     token: String,
     description: String,
-    created: chrono::DateTime<chrono::Local>,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(serialize_with = "timezone::serialize")]
+    created: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    archived: bool,
 }
 impl Token {
     fn new<T: AsRef<str>>(description: T) -> Self {
-        Self {
-            token: uuid::Uuid::new_v4()
+        Self::with_token(
+            uuid::Uuid::new_v4()
                 .to_simple()
-                .encode_lower(&mut uuid::Uuid::encode_buffer())
-                .to_string(),
+                .encode_lower(&mut uuid::Uuid::encode_buffer()),
+            description,
+        )
+    }
+
+    fn with_token<T: AsRef<str>, U: AsRef<str>>(token: T, description: U) -> Self {
+        Self {
+            token: token.as_ref().to_string(),
             description: description.as_ref().to_string(),
-            created: chrono::Local::now(),
+            labels: vec![],
+            created: chrono::Utc::now(),
+            archived: false,
         }
     }
+
+    /// Check that a client provided token is usable in a `/p/{token}` url
+    fn validate_vanity(token: &str) -> Result<()> {
+        lazy_static::lazy_static! {
+            // keep in sync with the `token` capture in `service::route`
+            static ref VALID: regex::Regex = regex::Regex::new(r"^[a-zA-Z0-9-_]+$").unwrap();
+        }
+        if token.len() > MAX_TOKEN_LEN {
+            Err(ErrorKind::BadRequest(format!(
+                "token must be at most {} characters",
+                MAX_TOKEN_LEN
+            )))?
+        }
+        if !VALID.is_match(token) {
+            Err(ErrorKind::BadRequest(format!(
+                "token `{}` must only contain [a-zA-Z0-9-_]",
+                token
+            )))?
+        }
+        if RESERVED_TOKENS.contains(&token) {
+            Err(ErrorKind::BadRequest(format!(
+                "token `{}` is reserved",
+                token
+            )))?
+        }
+        Ok(())
+    }
 }
 impl redis::FromRedisValue for Token {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Token> {
@@ -61,44 +109,366 @@ impl redis::FromRedisValue for Token {
 }
 
 #[derive(Deserialize)]
-struct CreateToken<'a> {
-    description: &'a str,
+struct CreateToken {
+    description: String,
+    #[serde(default)]
+    labels: Vec<String>,
+    token: Option<String>,
+}
+impl CreateToken {
+    fn into_token(self) -> Result<Token> {
+        let mut token = match self.token {
+            Some(vanity) => {
+                Token::validate_vanity(&vanity)?;
+                Token::with_token(vanity, self.description)
+            }
+            None => Token::new(self.description),
+        };
+        token.labels = self.labels;
+        Ok(token)
+    }
+}
+
+/// Lua script that claims a token in the global registry and, only if the
+/// owner's token quota has room for it and nobody else owns it yet, saves it
+/// under the owner and records the audit entry of its creation. Returns `-1`
+/// if the quota is used up, `0` if the token is taken and `1` once claimed.
+///
+/// KEYS: registry, owner tokens hash, audit log, quotas
+/// ARGV: token, owner, token json, audit entry json
+static CLAIM_TOKEN: &str = r#"
+local quota = redis.call('HGET', KEYS[4], ARGV[2])
+if quota then
+    local limit = cjson.decode(quota)['max_tokens']
+    if limit and limit ~= cjson.null and redis.call('HLEN', KEYS[2]) >= limit then
+        return -1
+    end
+end
+if redis.call('HSETNX', KEYS[1], ARGV[1], ARGV[2]) == 1 then
+    redis.call('HSET', KEYS[2], ARGV[1], ARGV[3])
+    redis.call('RPUSH', KEYS[3], ARGV[4])
+    return 1
+end
+return 0
+"#;
+
+/// Build the command claiming `token` for `owner` on behalf of `actor`, see
+/// `claimed` for what it returns
+fn claim_token(owner: &str, token: &Token, actor: &Actor) -> Result<redis::Cmd> {
+    let mut cmd = redis::cmd("EVAL");
+    cmd.arg(CLAIM_TOKEN)
+        .arg(4)
+        .arg(TOKEN_REGISTRY)
+        .arg(format!("mpix.user_tokens:{}", owner))
+        .arg(audit::AUDIT_LOG)
+        .arg(quotas::QUOTAS)
+        .arg(&token.token)
+        .arg(owner)
+        .arg(serde_json::to_string(token)?)
+        .arg(audit::entry(actor, Action::TokenCreate, &token.token)?);
+    Ok(cmd)
+}
+
+fn token_conflict(token: &str) -> ErrorKind {
+    ErrorKind::Conflict(format!("token `{}` already exists", token))
+}
+
+/// Whether `claim_token` claimed `token`, given what it returned
+fn claimed(claim: i64, token: &str) -> Result<()> {
+    match claim {
+        -1 => Err(quotas::token_quota_exhausted())?,
+        0 => Err(token_conflict(token))?,
+        _ => Ok(()),
+    }
+}
+
+/// Public url of `path`, as seen by the requesting client
+fn public_url(request: &Request<Body>, path: &str) -> String {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|hv: &HeaderValue| hv.to_str().ok())
+    };
+    format!(
+        "{}://{}{}",
+        header("x-forwarded-proto").unwrap_or("http"),
+        header("host").unwrap_or("localhost"),
+        path
+    )
+}
+
+/// Parse the request's query string into `T`
+fn query<T: serde::de::DeserializeOwned>(request: &Request<Body>) -> Result<T> {
+    let query = request.uri().query().unwrap_or("");
+    Ok(serde_urlencoded::from_str(query)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid query parameters: {}", e)))?)
+}
+
+/// Public url of a token's tracking pixel
+fn pixel_url(request: &Request<Body>, token: &str) -> String {
+    public_url(request, &format!("/p/{}", token))
 }
 
 pub async fn create(ctx: Context) -> Result<Response<Body>> {
     let auth = ctx
         .auth
         .ok_or_else(|| "in an authorized context without a token")?;
+    let actor = Actor::new(&auth, &ctx.request);
     let body = ctx.request.into_body().compat().try_concat().await?;
-    let token_args: CreateToken =
-        serde_json::from_slice(&body).map_err(|e| format!("Invalid create token input: {}", e))?;
-    let token = Token::new(token_args.description);
-    let token_str = serde_json::to_string(&token)?;
+    let token_args: CreateToken = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid create token input: {}", e)))?;
+    let token = token_args.into_token()?;
+    let namespace = creation_namespace(&auth)?;
     let conn = ctx.redis.get_async_connection().compat().await?;
-    let key = format!("mpix.user_tokens:{}", auth.user_token);
-    let _: (_, ()) = redis::cmd("HSET")
-        .arg(key)
-        .arg(token.token)
-        .arg(&token_str)
+    let (_, claim): (_, i64) = claim_token(&namespace, &token, &actor)?
         .query_async(conn)
         .compat()
         .await?;
+    claimed(claim, &token.token)?;
 
     let r = Response::builder()
         .header("content-type", "application/json")
-        .body(Body::from(token_str))?;
+        .body(Body::from(serde_json::to_string(&token)?))?;
+    Ok(r)
+}
+
+/// Maximum number of tokens that can be created by a single bulk request
+const MAX_BULK_CREATE: usize = 1000;
+
+/// Csv form of `CreateToken`, `labels` are `;` separated
+#[derive(Deserialize)]
+struct CsvCreateToken {
+    description: String,
+    #[serde(default)]
+    labels: String,
+    #[serde(default)]
+    token: Option<String>,
+}
+impl From<CsvCreateToken> for CreateToken {
+    fn from(row: CsvCreateToken) -> CreateToken {
+        CreateToken {
+            description: row.description,
+            labels: row
+                .labels
+                .split(';')
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .map(String::from)
+                .collect(),
+            token: row.token.filter(|token| !token.is_empty()),
+        }
+    }
+}
+
+/// Outcome of creating a single row of a bulk request
+#[derive(Serialize)]
+struct BulkCreated {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<Token>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pixel_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+impl BulkCreated {
+    fn failed<E: std::fmt::Display>(index: usize, error: E) -> Self {
+        Self {
+            index,
+            token: None,
+            pixel_url: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Flattened `BulkCreated` written as a csv row
+#[derive(Serialize)]
+struct CsvBulkCreated<'a> {
+    index: usize,
+    token: &'a str,
+    description: &'a str,
+    labels: String,
+    created: String,
+    pixel_url: &'a str,
+    error: &'a str,
+}
+impl<'a> From<&'a BulkCreated> for CsvBulkCreated<'a> {
+    fn from(res: &'a BulkCreated) -> CsvBulkCreated<'a> {
+        let token = res.token.as_ref();
+        CsvBulkCreated {
+            index: res.index,
+            token: token.map(|t| t.token.as_str()).unwrap_or(""),
+            description: token.map(|t| t.description.as_str()).unwrap_or(""),
+            labels: token.map(|t| t.labels.join(";")).unwrap_or_default(),
+            created: token.map(|t| t.created.to_rfc3339()).unwrap_or_default(),
+            pixel_url: res.pixel_url.as_deref().unwrap_or(""),
+            error: res.error.as_deref().unwrap_or(""),
+        }
+    }
+}
+
+fn parse_bulk_json(body: &[u8]) -> Result<Vec<Result<CreateToken>>> {
+    let rows: Vec<serde_json::Value> = serde_json::from_slice(body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Expected a json array of tokens: {}", e)))?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            Ok(serde_json::from_value(row)
+                .map_err(|e| ErrorKind::BadRequest(format!("Invalid create token input: {}", e)))?)
+        })
+        .collect())
+}
+
+fn parse_bulk_csv(body: &[u8]) -> Vec<Result<CreateToken>> {
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(body)
+        .deserialize::<CsvCreateToken>()
+        .map(|row| {
+            Ok(row
+                .map_err(|e| ErrorKind::BadRequest(format!("Invalid csv row: {}", e)))?
+                .into())
+        })
+        .collect()
+}
+
+/// Create many tokens at once from either a json array of `CreateToken`
+/// objects or a csv upload (`content-type: text/csv`) with `description`,
+/// `labels`, and `token` columns. Rows are created independently and the
+/// results are returned in the same format as the input.
+pub async fn create_bulk(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let is_csv = ctx
+        .request
+        .headers()
+        .get("content-type")
+        .map(|ct| ct.to_str().unwrap_or("").starts_with("text/csv"))
+        .unwrap_or(false);
+    let actor = Actor::new(&auth, &ctx.request);
+    let (parts, body) = ctx.request.into_parts();
+    let request = Request::from_parts(parts, Body::empty());
+    let body = body.compat().try_concat().await?;
+    let rows = if is_csv {
+        parse_bulk_csv(&body)
+    } else {
+        parse_bulk_json(&body)?
+    };
+    if rows.len() > MAX_BULK_CREATE {
+        Err(ErrorKind::BadRequest(format!(
+            "at most {} tokens can be created at once",
+            MAX_BULK_CREATE
+        )))?
+    }
+
+    let namespace = creation_namespace(&auth)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let mut results = Vec::with_capacity(rows.len());
+    let mut claiming = vec![];
+    let mut pipe = redis::pipe();
+    for (index, row) in rows.into_iter().enumerate() {
+        match row.and_then(CreateToken::into_token) {
+            Ok(token) => {
+                pipe.add_command(claim_token(&namespace, &token, &actor)?);
+                claiming.push(index);
+                results.push(BulkCreated {
+                    index,
+                    pixel_url: Some(pixel_url(&request, &token.token)),
+                    token: Some(token),
+                    error: None,
+                });
+            }
+            Err(e) => results.push(BulkCreated::failed(index, e)),
+        }
+    }
+
+    if !claiming.is_empty() {
+        // rows past the quota fail, the ones before it are still created
+        let (_, claims): (_, Vec<i64>) = pipe.query_async(conn).compat().await?;
+        for (index, claim) in claiming.into_iter().zip(claims) {
+            let token = results[index].token.as_ref().map(|t| t.token.clone());
+            if let Err(e) = claimed(claim, &token.unwrap_or_default()) {
+                results[index] = BulkCreated::failed(index, e);
+            }
+        }
+    }
+
+    let created = results.iter().filter(|r| r.error.is_none()).count();
+    slog::debug!(LOG, "bulk created tokens";
+                 "created" => created, "failed" => results.len() - created);
+    let r = if is_csv {
+        let mut w = csv::Writer::from_writer(vec![]);
+        for res in &results {
+            w.serialize(CsvBulkCreated::from(res))?;
+        }
+        let bytes = w
+            .into_inner()
+            .map_err(|e| format!("error flushing csv writer {:?}", e))?;
+        Response::builder()
+            .header("content-type", "text/csv")
+            .body(Body::from(bytes))?
+    } else {
+        Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_string(&results)?))?
+    };
     Ok(r)
 }
 
 #[derive(Serialize, Deserialize)]
 struct TokenData {
-    created: chrono::DateTime<chrono::Local>,
+    #[serde(serialize_with = "timezone::serialize")]
+    created: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+}
+/// Address of the client a request was forwarded for by a proxy
+pub(crate) fn forwarded_ip(request: &Request<Body>) -> Option<String> {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|hv: &HeaderValue| hv.to_str().ok())
+    };
+    // the first forwarded address is the client, the rest are proxies
+    header("x-forwarded-for")
+        .and_then(|ips| ips.split(',').next())
+        .or_else(|| header("x-real-ip"))
+        .map(|ip| ip.trim().to_string())
 }
+
 impl TokenData {
-    fn new() -> Self {
+    fn new(request: &Request<Body>) -> Self {
         Self {
-            created: chrono::Local::now(),
+            created: chrono::Utc::now(),
+            ip: forwarded_ip(request),
+            user_agent: request
+                .headers()
+                .get("user-agent")
+                .and_then(|hv| hv.to_str().ok())
+                .map(String::from),
+        }
+    }
+
+    /// Identifies who opened the pixel, for counting unique opens
+    fn visitor(&self) -> Option<String> {
+        if self.ip.is_none() && self.user_agent.is_none() {
+            return None;
         }
+        Some(format!(
+            "{}|{}",
+            self.ip.as_deref().unwrap_or(""),
+            self.user_agent.as_deref().unwrap_or("")
+        ))
+    }
+
+    /// Score of the event in its token's time index
+    fn score(&self) -> i64 {
+        self.created.timestamp_millis()
     }
 }
 impl redis::FromRedisValue for TokenData {
@@ -114,6 +484,23 @@ impl redis::FromRedisValue for TokenData {
     }
 }
 
+/// Lua script that records an event in its token's list and time index. Each
+/// event is numbered by the token's sequence, which is stored zero padded as
+/// the first field of its json, so events sharing a score are indexed in the
+/// order they were recorded.
+///
+/// KEYS: events list, events index, events sequence
+/// ARGV: event json, event score, most events kept
+static RECORD_EVENT: &str = r#"
+local seq = redis.call('INCR', KEYS[3])
+local event = string.format('{"seq":"%020d",', seq) .. string.sub(ARGV[1], 2)
+redis.call('LPUSH', KEYS[1], event)
+redis.call('LTRIM', KEYS[1], 0, ARGV[3])
+redis.call('ZADD', KEYS[2], ARGV[2], event)
+redis.call('ZREMRANGEBYRANK', KEYS[2], 0, -(tonumber(ARGV[3]) + 2))
+return seq
+"#;
+
 pub async fn track(ctx: Context) -> Result<Response<Body>> {
     lazy_static::lazy_static! {
         static ref PIXEL: Vec<u8> = base64::decode(
@@ -121,97 +508,2167 @@ pub async fn track(ctx: Context) -> Result<Response<Body>> {
         ).expect("pixel is invalid base64");
     }
 
+    let pixel = || -> Result<Response<Body>> {
+        Ok(Response::builder()
+            .header("content-type", "image/png")
+            .body(Body::from(PIXEL.as_slice()))?)
+    };
+
+    // `HEAD` requests are answered like `GET` ones but mustn't record a hit
+    if ctx.request.method() == Method::HEAD {
+        return pixel();
+    }
     let token = ctx.captures.get("token")?;
-    let data = TokenData::new();
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let mut pipe = redis::pipe();
+    pipe.cmd("SISMEMBER")
+        .arg(ARCHIVED_TOKENS)
+        .arg(&token)
+        .cmd("HGET")
+        .arg(TOKEN_REGISTRY)
+        .arg(&token);
+    let (conn, (archived, owner)): (_, (bool, Option<String>)) =
+        pipe.query_async(conn).compat().await?;
+    if archived {
+        slog::debug!(LOG, "skipped archived token"; "token" => token);
+        metrics::PIXEL_HITS_DROPPED
+            .with_label_values(&["archived"])
+            .inc();
+        return pixel();
+    }
+    let conn = match owner {
+        Some(owner) => {
+            let (conn, taken) = quotas::take_event(conn, &owner).await?;
+            if !taken {
+                slog::debug!(LOG, "skipped token over its owner's event quota"; "token" => token);
+                metrics::PIXEL_HITS_DROPPED
+                    .with_label_values(&["quota"])
+                    .inc();
+                return pixel();
+            }
+            conn
+        }
+        None => conn,
+    };
+
+    let data = TokenData::new(&ctx.request);
     let data_str = serde_json::to_string(&data)?;
     let list_key = format!("mpix.token:{}", token);
-    let conn = ctx.redis.get_async_connection().compat().await?;
+    let index_key = format!("mpix.token_events:{}", token);
     let mut pipe = redis::Pipeline::new();
     pipe.atomic()
         .atomic()
-        .cmd("LPUSH")
+        .cmd("EVAL")
+        .arg(RECORD_EVENT)
+        .arg(3)
         .arg(&list_key)
-        .arg(data_str)
+        .arg(&index_key)
+        .arg(format!("mpix.token_seq:{}", token))
+        .arg(&data_str)
+        .arg(data.score())
+        .arg(MAX_TOKEN_EVENTS)
         .ignore()
-        .cmd("LTRIM")
-        .arg(&list_key)
-        .arg(0)
-        .arg(200)
+        .cmd("INCR")
+        .arg(format!("mpix.token_opens:{}", token))
         .ignore()
-        .cmd("LLEN")
-        .arg(&list_key);
-    let (_, (count,)): (_, (usize,)) = pipe.query_async(conn).compat().await?;
+        .cmd("SET")
+        .arg(format!("mpix.token_first_open:{}", token))
+        .arg(data.created.to_rfc3339())
+        .arg("NX")
+        .ignore();
+    if let Some(visitor) = data.visitor() {
+        pipe.cmd("PFADD")
+            .arg(format!("mpix.token_visitors:{}", token))
+            .arg(visitor)
+            .ignore();
+    }
+    pipe.cmd("LLEN").arg(&list_key);
+    let (_, (count,)): (_, (usize,)) = match pipe.query_async(conn).compat().await {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            metrics::PIXEL_HITS_DROPPED
+                .with_label_values(&["error"])
+                .inc();
+            Err(e)?
+        }
+    };
+    metrics::PIXEL_HITS_RECORDED.inc();
 
     slog::debug!(LOG, "tracked token"; "token" => token, "count" => count);
+    pixel()
+}
+
+/// Whether `caller` owns a token. Tokens are owned by whoever claimed them in
+/// the global registry, tokens created before the registry existed are only
+/// listed under their owner's tokens hash.
+fn is_owner(caller: &str, registered_owner: Option<&str>, listed_for_caller: bool) -> bool {
+    match registered_owner {
+        Some(owner) => owner == caller,
+        None => listed_for_caller,
+    }
+}
+
+/// Level of access a user has to a token, from least to most privileged
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Access {
+    /// Read the token's stats
+    Read,
+    /// Read the token's stats and manage who it's shared with
+    Manage,
+    /// Created the token
+    Owner,
+}
+impl Access {
+    fn as_str(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Manage => "manage",
+            Access::Owner => "owner",
+        }
+    }
+}
+impl std::str::FromStr for Access {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "read" => Access::Read,
+            "manage" => Access::Manage,
+            s => Err(format!("Invalid access: {}", s))?,
+        })
+    }
+}
+
+/// Resolve the access `caller` has to a token, owners always have full
+/// access while everybody else only has what was granted to them
+fn resolve_access(
+    caller: &str,
+    registered_owner: Option<&str>,
+    listed_for_caller: bool,
+    granted: Option<Access>,
+) -> Option<Access> {
+    if is_owner(caller, registered_owner, listed_for_caller) {
+        Some(Access::Owner)
+    } else {
+        granted
+    }
+}
+
+/// Queue the reads `caller_access` resolves a caller's access to `token` from
+fn access_cmds(pipe: &mut redis::Pipeline, auth: &Auth, token: &str) {
+    pipe.cmd("HGET")
+        .arg(TOKEN_REGISTRY)
+        .arg(token)
+        .cmd("HEXISTS")
+        .arg(format!("mpix.user_tokens:{}", auth.user_token))
+        .arg(token)
+        .cmd("HGET")
+        .arg(format!("mpix.token_access:{}", token))
+        .arg(&auth.user_name);
+}
+
+/// Access members of an org have to the org's tokens
+fn role_access(role: Role) -> Access {
+    match role {
+        Role::Viewer => Access::Read,
+        Role::Member | Role::Admin | Role::Owner => Access::Owner,
+    }
+}
+
+/// Access the caller has to the tokens of an org namespace they're a member of
+fn org_access(auth: &Auth, namespace: &str) -> Option<Access> {
+    auth.orgs
+        .iter()
+        .find(|membership| membership.namespace() == namespace)
+        .map(|membership| role_access(membership.role))
+}
+
+/// The access the caller has to a token, from the values read by `access_cmds`.
+/// Tokens owned by the caller's orgs are reached through their role, and
+/// callers that can't reach their personal data can't reach what was shared
+/// with them either.
+fn caller_access(
+    auth: &Auth,
+    registered_owner: Option<String>,
+    listed: bool,
+    granted: Option<String>,
+) -> Result<Option<Access>> {
+    let through_org = registered_owner
+        .as_deref()
+        .and_then(|owner| org_access(auth, owner));
+    if !auth.personal {
+        return Ok(through_org);
+    }
+    let granted = match granted {
+        Some(granted) => Some(granted.parse::<Access>()?),
+        None => None,
+    };
+    let personal = resolve_access(
+        &auth.user_token,
+        registered_owner.as_deref(),
+        listed,
+        granted,
+    );
+    Ok(personal.max(through_org))
+}
+
+/// Namespace the caller creates tokens in, viewers can't create tokens in
+/// their org
+fn creation_namespace(auth: &Auth) -> Result<String> {
+    match auth.org.as_ref() {
+        Some(org) if org.role < Role::Member => Err(ErrorKind::Forbidden(format!(
+            "viewers can't create tokens in organization `{}`",
+            org.org
+        )))?,
+        _ => Ok(auth.namespace()),
+    }
+}
+
+/// Make sure the caller has at least `required` access to `token`. Tokens
+/// the caller can't see at all are reported as missing so their existence
+/// isn't leaked.
+async fn ensure_access(
+    conn: RedisConnection,
+    auth: &Auth,
+    token: &str,
+    required: Access,
+) -> Result<(RedisConnection, Access)> {
+    let mut pipe = redis::pipe();
+    access_cmds(&mut pipe, auth, token);
+    let (conn, (registered_owner, listed, granted)): (_, (Option<String>, bool, Option<String>)) =
+        pipe.query_async(conn).compat().await?;
+    let access = caller_access(auth, registered_owner, listed, granted)?;
+    Ok((conn, require_access(access, token, required)?))
+}
+
+/// Turn the access the caller has to `token` into an error unless it's at
+/// least `required`, see `ensure_access`
+fn require_access(access: Option<Access>, token: &str, required: Access) -> Result<Access> {
+    match access {
+        None => Err(ErrorKind::DoesNotExist(format!(
+            "token `{}` not found",
+            token
+        )))?,
+        Some(access) if access < required => Err(ErrorKind::Forbidden(format!(
+            "`{}` access to token `{}` is required",
+            required.as_str(),
+            token
+        )))?,
+        Some(access) => Ok(access),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Grant {
+    user: String,
+    access: Access,
+}
+
+/// Share a token with another existing user
+pub async fn grant_access(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let actor = Actor::new(&auth, &ctx.request);
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let grant: Grant = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid grant input: {}", e)))?;
+    if grant.access == Access::Owner {
+        Err(ErrorKind::BadRequest(
+            "tokens can only be shared with `read` or `manage` access".into(),
+        ))?
+    }
+    if grant.user == auth.user_name {
+        Err(ErrorKind::BadRequest(
+            "tokens can't be shared with yourself".into(),
+        ))?
+    }
+
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, access) = ensure_access(conn, &auth, &token, Access::Manage).await?;
+    let (conn, exists): (_, bool) = redis::cmd("HEXISTS")
+        .arg("mpix.accounts")
+        .arg(&grant.user)
+        .query_async(conn)
+        .compat()
+        .await?;
+    if !exists {
+        Err(ErrorKind::DoesNotExist(format!(
+            "user `{}` not found",
+            grant.user
+        )))?
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HSET")
+        .arg(format!("mpix.token_access:{}", token))
+        .arg(&grant.user)
+        .arg(grant.access.as_str())
+        .ignore()
+        .cmd("HSET")
+        .arg(format!("mpix.user_shared:{}", grant.user))
+        .arg(&token)
+        .arg(grant.access.as_str())
+        .ignore();
+    if access == Access::Owner {
+        // tokens created before the registry existed need to be
+        // registered so they can be found from the shared user's side
+        pipe.cmd("HSETNX")
+            .arg(TOKEN_REGISTRY)
+            .arg(&token)
+            .arg(&auth.user_token)
+            .ignore();
+    }
+    let target = format!("{}/{}", token, grant.user);
+    audit::record(&mut pipe, &actor, Action::AccessGrant, &target)?;
+    let (_, ()) = pipe.query_async(conn).compat().await?;
+
+    slog::debug!(LOG, "granted token access";
+                 "token" => &token, "user" => &grant.user, "access" => grant.access.as_str());
     let r = Response::builder()
-        .header("content-type", "image/png")
-        .body(Body::from(PIXEL.as_slice()))?;
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&grant)?))?;
     Ok(r)
 }
 
-pub async fn tracking_stats(ctx: Context) -> Result<Response<Body>> {
-    let auth = ctx
-        .auth
-        .ok_or_else(|| "in an authorized context without a token")?;
+/// List the users a token is shared with
+pub async fn list_access(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
     let conn = ctx.redis.get_async_connection().compat().await?;
-    match ctx.captures.get("token").ok() {
-        Some(token) => {
-            #[derive(Serialize)]
-            struct ReturnData {
-                events: Vec<TokenData>,
-            }
+    let (conn, _) = ensure_access(conn, &auth, &token, Access::Manage).await?;
+    let (_, granted): (_, HashMap<String, String>) = redis::cmd("HGETALL")
+        .arg(format!("mpix.token_access:{}", token))
+        .query_async(conn)
+        .compat()
+        .await?;
 
-            let key = format!("mpix.token:{}", token);
-            let (_, token_data): (_, Option<Vec<TokenData>>) = redis::cmd("LRANGE")
-                .arg(key)
-                .arg(0)
-                .arg(-1)
-                .query_async(conn)
-                .compat()
-                .await?;
-            let resp = ReturnData {
-                events: token_data.unwrap_or_else(|| vec![]),
-            };
-            Ok(Response::new(Body::from(serde_json::to_string(&resp)?)))
+    #[derive(Serialize)]
+    struct ReturnData {
+        grants: Vec<Grant>,
+    }
+    let mut grants = granted
+        .into_iter()
+        .map(|(user, access)| {
+            Ok(Grant {
+                user,
+                access: access.parse()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    grants.sort_by(|a, b| a.user.cmp(&b.user));
+    let resp = ReturnData { grants };
+    Ok(Response::new(Body::from(serde_json::to_string(&resp)?)))
+}
+
+/// Lua script that stops sharing a token with a user and, only if it was
+/// shared with them, records the audit entry of the change.
+///
+/// KEYS: token access hash, user's shared tokens hash, audit log
+/// ARGV: user, token, audit entry json
+static REVOKE_ACCESS: &str = r#"
+local removed = redis.call('HDEL', KEYS[1], ARGV[1])
+redis.call('HDEL', KEYS[2], ARGV[2])
+if removed == 1 then
+    redis.call('RPUSH', KEYS[3], ARGV[3])
+end
+return removed
+"#;
+
+/// Stop sharing a token with a user
+pub async fn revoke_access(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let user = ctx.captures.get("user")?;
+    let actor = Actor::new(&auth, &ctx.request);
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = ensure_access(conn, &auth, &token, Access::Manage).await?;
+    let target = format!("{}/{}", token, user);
+    let (_, removed): (_, bool) = redis::cmd("EVAL")
+        .arg(REVOKE_ACCESS)
+        .arg(3)
+        .arg(format!("mpix.token_access:{}", token))
+        .arg(format!("mpix.user_shared:{}", user))
+        .arg(audit::AUDIT_LOG)
+        .arg(&user)
+        .arg(&token)
+        .arg(audit::entry(&actor, Action::AccessRevoke, &target)?)
+        .query_async(conn)
+        .compat()
+        .await?;
+    if !removed {
+        Err(ErrorKind::DoesNotExist(format!(
+            "token `{}` is not shared with `{}`",
+            token, user
+        )))?
+    }
+
+    slog::debug!(LOG, "revoked token access"; "token" => &token, "user" => &user);
+    let r = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+/// A token in a user's token list along with the access they have to it
+#[derive(Serialize)]
+struct ListedToken {
+    #[serde(flatten)]
+    token: Token,
+    access: Access,
+    shared: bool,
+    /// Org that owns the token, if it isn't the caller's own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    org: Option<String>,
+}
+
+/// Tokens other users have shared with the caller, if they can reach their
+/// personal data
+async fn shared_tokens(
+    conn: RedisConnection,
+    auth: &Auth,
+) -> Result<(RedisConnection, Vec<ListedToken>)> {
+    if !auth.personal {
+        return Ok((conn, vec![]));
+    }
+    let (conn, shared): (_, HashMap<String, String>) = redis::cmd("HGETALL")
+        .arg(format!("mpix.user_shared:{}", auth.user_name))
+        .query_async(conn)
+        .compat()
+        .await?;
+    if shared.is_empty() {
+        return Ok((conn, vec![]));
+    }
+    let shared = shared
+        .into_iter()
+        .map(|(token, access)| Ok((token, access.parse::<Access>()?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut pipe = redis::pipe();
+    for (token, _) in &shared {
+        pipe.cmd("HGET").arg(TOKEN_REGISTRY).arg(token);
+    }
+    let (conn, owners): (_, Vec<Option<String>>) = pipe.query_async(conn).compat().await?;
+
+    let mut pipe = redis::pipe();
+    let mut found = vec![];
+    for ((token, access), owner) in shared.into_iter().zip(owners) {
+        if let Some(owner) = owner {
+            pipe.cmd("HGET")
+                .arg(format!("mpix.user_tokens:{}", owner))
+                .arg(&token);
+            found.push(access);
         }
-        None => {
-            #[derive(Serialize)]
-            struct ReturnData {
-                tokens: Vec<Token>,
+    }
+    if found.is_empty() {
+        return Ok((conn, vec![]));
+    }
+    let (conn, tokens): (_, Vec<Option<Token>>) = pipe.query_async(conn).compat().await?;
+    let listed = tokens
+        .into_iter()
+        .zip(found)
+        .filter_map(|(token, access)| {
+            token.map(|token| ListedToken {
+                token,
+                access,
+                shared: true,
+                org: None,
+            })
+        })
+        .collect();
+    Ok((conn, listed))
+}
+
+/// Parse a timestamp given as either rfc3339 or epoch seconds
+fn parse_timestamp(s: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    use chrono::TimeZone;
+    if let Ok(secs) = s.parse::<i64>() {
+        return Ok(chrono::Utc.timestamp(secs, 0));
+    }
+    // a `+` offset decodes to a space in query strings
+    let s = s.replace(' ', "+");
+    let ts = chrono::DateTime::parse_from_rfc3339(&s).map_err(|e| {
+        ErrorKind::BadRequest(format!(
+            "Invalid timestamp `{}`, expected rfc3339 or epoch seconds: {}",
+            s, e
+        ))
+    })?;
+    Ok(ts.with_timezone(&chrono::Utc))
+}
+
+/// Query parameters selecting a window of events
+#[derive(Deserialize, Default)]
+struct EventQuery {
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Window of events to read from a token's time index
+#[derive(Default, Debug, PartialEq)]
+struct EventRange {
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    limit: Option<usize>,
+}
+impl EventRange {
+    fn from_query(q: EventQuery) -> Result<Self> {
+        let range = Self {
+            since: q.since.as_deref().map(parse_timestamp).transpose()?,
+            until: q.until.as_deref().map(parse_timestamp).transpose()?,
+            limit: q.limit,
+        };
+        if range.limit == Some(0) {
+            Err(ErrorKind::BadRequest("limit must be at least 1".into()))?
+        }
+        if let (Some(since), Some(until)) = (range.since, range.until) {
+            if since > until {
+                Err(ErrorKind::BadRequest(
+                    "since must not be after until".into(),
+                ))?
             }
+        }
+        Ok(range)
+    }
+}
 
-            let key = format!("mpix.user_tokens:{}", auth.user_token);
-            let (_conn, tokens): (_, Option<Vec<Token>>) = redis::cmd("HVALS")
-                .arg(key)
-                .query_async(conn)
-                .compat()
-                .await?;
-            let resp = ReturnData {
-                tokens: tokens.unwrap_or_else(|| vec![]),
-            };
-            Ok(Response::new(Body::from(serde_json::to_string(&resp)?)))
+/// Longest a request can wait for new events
+const MAX_TAIL_WAIT_SECS: u64 = 60;
+
+/// How often a waiting request checks for new events
+const TAIL_POLL_MILLIS: u64 = 500;
+
+/// Position in a token's events, rendered as `{score}.{seen}`: the score of
+/// the newest event read and how many events with that score were read.
+/// Events are scored by the millisecond so a few can share a score, those are
+/// ordered by their sequence number, see `RECORD_EVENT`.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct Cursor {
+    score: i64,
+    seen: usize,
+}
+impl Cursor {
+    /// The cursor after reading `events`, which are ordered oldest first
+    fn advance<'a>(mut self, events: impl IntoIterator<Item = &'a TokenData>) -> Self {
+        for event in events {
+            if event.score() == self.score {
+                self.seen += 1;
+            } else {
+                self.score = event.score();
+                self.seen = 1;
+            }
         }
+        self
     }
 }
+impl std::str::FromStr for Cursor {
+    type Err = Error;
 
-#[derive(Serialize)]
-struct Status<'a, 'b> {
-    status: &'a str,
-    hash: &'b str,
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || ErrorKind::BadRequest(format!("Invalid cursor `{}`", s));
+        let mut parts = s.splitn(2, '.');
+        let score = parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(invalid)?;
+        let seen = parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(invalid)?;
+        Ok(Self { score, seen })
+    }
+}
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.score, self.seen)
+    }
 }
 
-pub async fn status(_ctx: Context) -> Result<Response<Body>> {
-    let st = Status {
-        status: "ok",
-        hash: include_str!("../commit_hash.txt").trim(),
+/// Query parameters for tailing a token's events
+#[derive(Deserialize, Default)]
+struct TailQuery {
+    after: Option<String>,
+    /// Seconds to wait for new events when there are none after the cursor
+    wait: Option<u64>,
+}
+
+/// Tailing requested by a `TailQuery`
+#[derive(Debug, PartialEq)]
+struct Tail {
+    after: Cursor,
+    wait: std::time::Duration,
+}
+impl Tail {
+    fn from_query(q: TailQuery, range: &EventRange) -> Result<Option<Self>> {
+        let after = match q.after {
+            Some(after) => after.parse::<Cursor>()?,
+            None if q.wait.is_some() => Err(ErrorKind::BadRequest(
+                "wait requires an after cursor".into(),
+            ))?,
+            None => return Ok(None),
+        };
+        if range.since.is_some() || range.until.is_some() {
+            Err(ErrorKind::BadRequest(
+                "after can't be combined with since or until".into(),
+            ))?
+        }
+        let wait = q.wait.unwrap_or(0);
+        if wait > MAX_TAIL_WAIT_SECS {
+            Err(ErrorKind::BadRequest(format!(
+                "wait can be at most {} seconds",
+                MAX_TAIL_WAIT_SECS
+            )))?
+        }
+        Ok(Some(Self {
+            after,
+            wait: std::time::Duration::from_secs(wait),
+        }))
+    }
+
+    /// `HEAD` requests only ask what's there already, they never wait
+    fn for_method(self, method: &Method) -> Self {
+        match *method {
+            Method::HEAD => Self {
+                wait: std::time::Duration::from_secs(0),
+                ..self
+            },
+            _ => self,
+        }
+    }
+}
+
+/// Index events recorded before the time index existed, which only live in
+/// the token's list
+async fn index_events(conn: RedisConnection, token: &str) -> Result<RedisConnection> {
+    let list_key = format!("mpix.token:{}", token);
+    let index_key = format!("mpix.token_events:{}", token);
+    let mut pipe = redis::pipe();
+    pipe.cmd("ZCARD").arg(&index_key).cmd("LLEN").arg(&list_key);
+    let (conn, (indexed, listed)): (_, (usize, usize)) = pipe.query_async(conn).compat().await?;
+    let conn = if indexed >= listed {
+        conn
+    } else {
+        // events recorded before the time index existed only live in the list,
+        // re-adding the ones that are already indexed is a no-op as long as the
+        // stored json is indexed as is
+        let (conn, events): (_, Vec<String>) = redis::cmd("LRANGE")
+            .arg(&list_key)
+            .arg(0)
+            .arg(-1)
+            .query_async(conn)
+            .compat()
+            .await?;
+        let mut cmd = redis::cmd("ZADD");
+        cmd.arg(&index_key);
+        for raw in &events {
+            let event: TokenData = serde_json::from_str(raw)?;
+            cmd.arg(event.score()).arg(raw);
+        }
+        let (conn, _): (_, ()) = cmd.query_async(conn).compat().await?;
+        slog::debug!(LOG, "indexed token events"; "token" => token, "count" => events.len());
+        conn
     };
-    let status = serde_json::to_string(&st)?;
-    Ok(Response::new(Body::from(status)))
+    Ok(conn)
 }
 
-pub async fn not_found(_ctx: Context) -> Result<Response<Body>> {
-    let r = Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::from("not found"))?;
-    Ok(r)
+/// Recorded events of a token within `range`, newest first
+async fn token_events(
+    conn: RedisConnection,
+    token: &str,
+    range: &EventRange,
+) -> Result<(RedisConnection, Vec<TokenData>)> {
+    let index_key = format!("mpix.token_events:{}", token);
+    let conn = index_events(conn, token).await?;
+
+    let mut cmd = redis::cmd("ZREVRANGEBYSCORE");
+    cmd.arg(&index_key)
+        .arg(
+            range
+                .until
+                .map(|ts| ts.timestamp_millis().to_string())
+                .unwrap_or_else(|| "+inf".into()),
+        )
+        .arg(
+            range
+                .since
+                .map(|ts| ts.timestamp_millis().to_string())
+                .unwrap_or_else(|| "-inf".into()),
+        );
+    if let Some(limit) = range.limit {
+        cmd.arg("LIMIT").arg(0).arg(limit);
+    }
+    let (conn, events): (_, Vec<TokenData>) = cmd.query_async(conn).compat().await?;
+    Ok((conn, events))
+}
+
+/// Up to `limit` of a token's events after `cursor`, oldest first
+async fn events_after(
+    conn: RedisConnection,
+    token: &str,
+    cursor: Cursor,
+    limit: Option<usize>,
+) -> Result<(RedisConnection, Vec<TokenData>)> {
+    let index_key = format!("mpix.token_events:{}", token);
+    let count = limit.map(|l| l as isize).unwrap_or(-1);
+    // events sharing the cursor's score are skipped by how many were read,
+    // everything newer is read from the start
+    let mut pipe = redis::pipe();
+    pipe.cmd("ZRANGEBYSCORE")
+        .arg(&index_key)
+        .arg(cursor.score)
+        .arg(cursor.score)
+        .arg("LIMIT")
+        .arg(cursor.seen)
+        .arg(count)
+        .cmd("ZRANGEBYSCORE")
+        .arg(&index_key)
+        .arg(format!("({}", cursor.score))
+        .arg("+inf")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(count);
+    let (conn, (mut events, newer)): (_, (Vec<TokenData>, Vec<TokenData>)) =
+        pipe.query_async(conn).compat().await?;
+    events.extend(newer);
+    if let Some(limit) = limit {
+        events.truncate(limit);
+    }
+    Ok((conn, events))
+}
+
+/// Events after the `tail` cursor, oldest first, waiting up to `tail.wait`
+/// for some to be recorded when there are none yet
+async fn tail_events(
+    conn: RedisConnection,
+    token: &str,
+    tail: &Tail,
+    limit: Option<usize>,
+) -> Result<(RedisConnection, Vec<TokenData>)> {
+    use std::time::{Duration, Instant};
+
+    let deadline = Instant::now() + tail.wait;
+    let mut conn = index_events(conn, token).await?;
+    loop {
+        let (c, events) = events_after(conn, token, tail.after, limit).await?;
+        let now = Instant::now();
+        if !events.is_empty() || now >= deadline {
+            return Ok((c, events));
+        }
+        conn = c;
+        let next = std::cmp::min(now + Duration::from_millis(TAIL_POLL_MILLIS), deadline);
+        tokio_timer::Delay::new(next)
+            .compat()
+            .await
+            .map_err(|e| format!("error waiting for events {:?}", e))?;
+    }
+}
+
+/// Counters kept for a token as events are recorded, these cover every
+/// open while the stored events only cover the most recent ones
+#[derive(Default)]
+struct Counters {
+    total_opens: usize,
+    unique_opens: usize,
+    first_open: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+async fn token_counters(conn: RedisConnection, token: &str) -> Result<(RedisConnection, Counters)> {
+    let mut pipe = redis::pipe();
+    pipe.cmd("GET")
+        .arg(format!("mpix.token_opens:{}", token))
+        .cmd("PFCOUNT")
+        .arg(format!("mpix.token_visitors:{}", token))
+        .cmd("GET")
+        .arg(format!("mpix.token_first_open:{}", token));
+    let (conn, (total_opens, unique_opens, first_open)): (
+        _,
+        (Option<usize>, usize, Option<String>),
+    ) = pipe.query_async(conn).compat().await?;
+    let first_open = first_open
+        .and_then(|ts| chrono::DateTime::parse_from_rfc3339(&ts).ok())
+        .map(|ts| ts.with_timezone(&chrono::Utc));
+    let counters = Counters {
+        total_opens: total_opens.unwrap_or(0),
+        unique_opens,
+        first_open,
+    };
+    Ok((conn, counters))
+}
+
+#[derive(Serialize)]
+struct Summary {
+    total_opens: usize,
+    unique_opens: usize,
+    #[serde(serialize_with = "timezone::serialize_opt")]
+    first_open: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(serialize_with = "timezone::serialize_opt")]
+    last_open: Option<chrono::DateTime<chrono::Utc>>,
+    /// Seconds from the token being created to its first open
+    secs_to_first_open: Option<i64>,
+    /// Opens keyed by `YYYY-MM-DD` in the requested timezone
+    opens_per_day: BTreeMap<String, usize>,
+    /// Opens by day of the week, monday first, then hour of the day, in the requested timezone
+    hour_of_week: [[usize; 24]; 7],
+}
+impl Summary {
+    /// Summarize a token from its counters and stored events, which are
+    /// ordered newest first. Tokens that recorded events before counters
+    /// were kept fall back to what can be counted from their events.
+    fn new(token: &Token, events: &[TokenData], counters: Counters, tz: Tz) -> Self {
+        use chrono::{Datelike, Timelike};
+
+        let mut visitors = HashSet::new();
+        let mut anonymous = 0;
+        let mut opens_per_day = BTreeMap::new();
+        let mut hour_of_week = [[0; 24]; 7];
+        for event in events {
+            match event.visitor() {
+                Some(visitor) => {
+                    visitors.insert(visitor);
+                }
+                None => anonymous += 1,
+            }
+            let created = event.created.with_timezone(&tz);
+            *opens_per_day
+                .entry(created.format("%Y-%m-%d").to_string())
+                .or_insert(0) += 1;
+            let weekday = created.weekday().num_days_from_monday() as usize;
+            hour_of_week[weekday][created.hour() as usize] += 1;
+        }
+
+        let oldest = events.last().map(|e| e.created);
+        let first_open = match (counters.first_open, oldest) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Self {
+            total_opens: counters.total_opens.max(events.len()),
+            unique_opens: counters.unique_opens.max(visitors.len() + anonymous),
+            first_open,
+            last_open: events.first().map(|e| e.created),
+            secs_to_first_open: first_open.map(|first| (first - token.created).num_seconds()),
+            opens_per_day,
+            hour_of_week,
+        }
+    }
+}
+
+/// Etag source for a response computed from `content` and rendered for
+/// `request`, whose query and timezone also change what gets rendered
+fn etag_source(request: &Request<Body>, content: String) -> Result<ETagSource> {
+    let tz = request
+        .headers()
+        .get(timezone::TZ_HEADER)
+        .map(|hv| hv.to_str())
+        .transpose()?
+        .unwrap_or("");
+    let query = request.uri().query().unwrap_or("");
+    Ok(ETagSource(format!("{}\n{}\n{}", content, query, tz)))
+}
+
+/// What a token's rendered events depend on, its event count and latest event
+fn events_version(token: &str, events: &[TokenData]) -> String {
+    let latest = events.first().map(TokenData::score).unwrap_or(0);
+    format!("{}\n{}\n{}", token, events.len(), latest)
+}
+
+/// Summary statistics of a token's opens
+pub async fn token_summary(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = ensure_access(conn, &auth, &token, Access::Read).await?;
+    let (conn, _, found) = load_token(conn, &auth, &token).await?;
+    let (conn, events) = token_events(conn, &token, &EventRange::default()).await?;
+    let (_, counters) = token_counters(conn, &token).await?;
+    let tz = timezone::from_request(&ctx.request)?;
+    let version = format!(
+        "{}\n{}",
+        events_version(&token, &events),
+        counters.total_opens
+    );
+    let summary = Summary::new(&found, &events, counters, tz);
+    let mut r = Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&summary, tz)?))?;
+    r.extensions_mut()
+        .insert(etag_source(&ctx.request, version)?);
+    Ok(r)
+}
+
+/// Maximum number of tokens a single batch stats request can cover
+const MAX_BATCH_STATS: usize = 1000;
+
+/// Most recent events a batch stats request can include for each token
+const MAX_BATCH_EVENTS: usize = 20;
+
+#[derive(Deserialize)]
+struct BatchStatsQuery {
+    tokens: Vec<String>,
+    /// Number of recent events to include for each token
+    #[serde(default)]
+    events: usize,
+}
+
+/// Stats of a single token of a batch request
+#[derive(Serialize)]
+struct BatchStats {
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_opens: Option<usize>,
+    #[serde(
+        serialize_with = "timezone::serialize_opt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    last_open: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    events: Option<Vec<TokenData>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+impl BatchStats {
+    fn failed<E: std::fmt::Display>(token: String, error: E) -> Self {
+        Self {
+            token,
+            total_opens: None,
+            last_open: None,
+            events: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Open counts and last opens of many tokens at once, in the order they
+/// were asked for. Tokens the caller can't read are reported as not found
+/// without failing the rest of the batch.
+pub async fn batch_stats(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let tz = timezone::from_request(&ctx.request)?;
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let q: BatchStatsQuery = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid batch stats input: {}", e)))?;
+    if q.tokens.len() > MAX_BATCH_STATS {
+        Err(ErrorKind::BadRequest(format!(
+            "stats of at most {} tokens can be read at once",
+            MAX_BATCH_STATS
+        )))?
+    }
+    if q.events > MAX_BATCH_EVENTS {
+        Err(ErrorKind::BadRequest(format!(
+            "at most {} events can be included per token",
+            MAX_BATCH_EVENTS
+        )))?
+    }
+    let mut seen = HashSet::new();
+    let tokens: Vec<String> = q
+        .tokens
+        .into_iter()
+        .filter(|token| seen.insert(token.clone()))
+        .collect();
+    if tokens.is_empty() {
+        return Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from("[]"))?);
+    }
+
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let mut pipe = redis::pipe();
+    for token in &tokens {
+        access_cmds(&mut pipe, &auth, token);
+    }
+    let (conn, values): (_, Vec<redis::Value>) = pipe.query_async(conn).compat().await?;
+    let mut readable = Vec::with_capacity(tokens.len());
+    for values in values.chunks(3) {
+        let access = caller_access(
+            &auth,
+            redis::from_redis_value(&values[0])?,
+            redis::from_redis_value(&values[1])?,
+            redis::from_redis_value(&values[2])?,
+        )?;
+        readable.push(access.is_some());
+    }
+
+    // the list holds the newest events of every token, including
+    // ones recorded before open counters and the time index existed
+    let newest = std::cmp::max(q.events, 1) as isize;
+    let mut pipe = redis::pipe();
+    for (token, _) in tokens.iter().zip(&readable).filter(|(_, r)| **r) {
+        let list_key = format!("mpix.token:{}", token);
+        pipe.cmd("GET")
+            .arg(format!("mpix.token_opens:{}", token))
+            .cmd("LLEN")
+            .arg(&list_key)
+            .cmd("LRANGE")
+            .arg(&list_key)
+            .arg(0)
+            .arg(newest - 1);
+    }
+    let values: Vec<redis::Value> = if readable.contains(&true) {
+        let (_, values) = pipe.query_async(conn).compat().await?;
+        values
+    } else {
+        vec![]
+    };
+    let mut values = values.chunks(3);
+
+    let mut results = Vec::with_capacity(tokens.len());
+    for (token, readable) in tokens.into_iter().zip(readable) {
+        if !readable {
+            let missing = ErrorKind::DoesNotExist(format!("token `{}` not found", token));
+            results.push(BatchStats::failed(token, Error::from(missing)));
+            continue;
+        }
+        let values = values.next().ok_or("missing batch stats reads")?;
+        let opens: Option<usize> = redis::from_redis_value(&values[0])?;
+        let listed: usize = redis::from_redis_value(&values[1])?;
+        let mut events: Vec<TokenData> = redis::from_redis_value(&values[2])?;
+        let last_open = events.first().map(|event| event.created);
+        events.truncate(q.events);
+        results.push(BatchStats {
+            token,
+            total_opens: Some(opens.unwrap_or(listed)),
+            last_open,
+            events: if q.events > 0 { Some(events) } else { None },
+            error: None,
+        });
+    }
+
+    let r = Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&results, tz)?))?;
+    Ok(r)
+}
+
+/// Default lifetime of a share link
+const DEFAULT_SHARE_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Longest lifetime a share link can be created with
+const MAX_SHARE_SECS: i64 = 90 * 24 * 60 * 60;
+
+/// A public, read-only link to a token's stats. Share ids are signed and
+/// carry their expiry so forged or expired links are rejected without a
+/// trip to redis, and revoked links are rejected by their missing record.
+#[derive(Serialize, Deserialize)]
+struct ShareLink {
+    share_id: String,
+    token: String,
+    created_by: String,
+    #[serde(serialize_with = "timezone::serialize")]
+    created: chrono::DateTime<chrono::Utc>,
+    #[serde(serialize_with = "timezone::serialize")]
+    expires: chrono::DateTime<chrono::Utc>,
+}
+impl ShareLink {
+    fn new<T: AsRef<str>, U: AsRef<str>>(token: T, created_by: U, ttl_secs: i64) -> Self {
+        let created = chrono::Utc::now();
+        let expires = created + chrono::Duration::seconds(ttl_secs);
+        let payload = format!(
+            "{}.{}",
+            uuid::Uuid::new_v4()
+                .to_simple()
+                .encode_lower(&mut uuid::Uuid::encode_buffer()),
+            expires.timestamp()
+        );
+        let signature = crypto::sign(&CONFIG.share_secret, &payload);
+        Self {
+            share_id: format!("{}.{}", payload, signature),
+            token: token.as_ref().to_string(),
+            created_by: created_by.as_ref().to_string(),
+            created,
+            expires,
+        }
+    }
+
+    /// Check a share id's signature and expiry
+    fn verify_id(share_id: &str) -> bool {
+        let mut parts = share_id.rsplitn(2, '.');
+        let (signature, payload) = match (parts.next(), parts.next()) {
+            (Some(signature), Some(payload)) => (signature, payload),
+            _ => return false,
+        };
+        let expires = payload
+            .rsplit('.')
+            .next()
+            .and_then(|ts| ts.parse::<i64>().ok());
+        match expires {
+            Some(expires) if expires > chrono::Utc::now().timestamp() => {
+                crypto::verify(&CONFIG.share_secret, payload, signature)
+            }
+            _ => false,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires <= chrono::Utc::now()
+    }
+}
+impl redis::FromRedisValue for ShareLink {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<ShareLink> {
+        match *v {
+            redis::Value::Data(ref bytes) => Ok(serde_json::from_slice(bytes)
+                .map_err(|_| (redis::ErrorKind::TypeError, "Invalid share link json bytes"))?),
+            _ => Err((
+                redis::ErrorKind::TypeError,
+                "Response type not share link compatible.",
+            ))?,
+        }
+    }
+}
+
+/// A share link along with the public url it can be viewed at
+#[derive(Serialize)]
+struct PublishedShareLink {
+    #[serde(flatten)]
+    link: ShareLink,
+    url: String,
+}
+
+#[derive(Deserialize, Default)]
+struct CreateShareLink {
+    expires_in: Option<i64>,
+}
+
+/// Mint a public link to a token's stats
+pub async fn create_share_link(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let actor = Actor::new(&auth, &ctx.request);
+    let (parts, body) = ctx.request.into_parts();
+    let request = Request::from_parts(parts, Body::empty());
+    let body = body.compat().try_concat().await?;
+    let args: CreateShareLink = if body.is_empty() {
+        CreateShareLink::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ErrorKind::BadRequest(format!("Invalid share link input: {}", e)))?
+    };
+    let ttl_secs = args.expires_in.unwrap_or(DEFAULT_SHARE_SECS);
+    if ttl_secs <= 0 || ttl_secs > MAX_SHARE_SECS {
+        Err(ErrorKind::BadRequest(format!(
+            "expires_in must be between 1 and {} seconds",
+            MAX_SHARE_SECS
+        )))?
+    }
+
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, access) = ensure_access(conn, &auth, &token, Access::Manage).await?;
+    let link = ShareLink::new(&token, &auth.user_name, ttl_secs);
+    let link_str = serde_json::to_string(&link)?;
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("SET")
+        .arg(format!("mpix.share:{}", link.share_id))
+        .arg(&link_str)
+        .arg("EX")
+        .arg(ttl_secs)
+        .ignore()
+        .cmd("HSET")
+        .arg(format!("mpix.token_shares:{}", token))
+        .arg(&link.share_id)
+        .arg(&link_str)
+        .ignore();
+    if access == Access::Owner {
+        // shared stats look up the token's owner through the registry
+        pipe.cmd("HSETNX")
+            .arg(TOKEN_REGISTRY)
+            .arg(&token)
+            .arg(&auth.user_token)
+            .ignore();
+    }
+    // share ids are bearer secrets, so only the token is recorded
+    audit::record(&mut pipe, &actor, Action::ShareCreate, &token)?;
+    let (_, ()) = pipe.query_async(conn).compat().await?;
+
+    slog::debug!(LOG, "created share link"; "token" => &token, "expires" => link.expires.to_rfc3339());
+    let published = PublishedShareLink {
+        url: public_url(&request, &format!("/share/{}", link.share_id)),
+        link,
+    };
+    let r = Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&published)?))?;
+    Ok(r)
+}
+
+/// List a token's share links that haven't expired
+pub async fn list_share_links(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = ensure_access(conn, &auth, &token, Access::Manage).await?;
+    let key = format!("mpix.token_shares:{}", token);
+    let (conn, links): (_, HashMap<String, ShareLink>) = redis::cmd("HGETALL")
+        .arg(&key)
+        .query_async(conn)
+        .compat()
+        .await?;
+
+    let (expired, mut links): (Vec<_>, Vec<_>) =
+        links.into_values().partition(ShareLink::is_expired);
+    if !expired.is_empty() {
+        let mut cmd = redis::cmd("HDEL");
+        cmd.arg(&key);
+        for link in &expired {
+            cmd.arg(&link.share_id);
+        }
+        let _: (_, ()) = cmd.query_async(conn).compat().await?;
+    }
+    links.sort_by_key(|link| std::cmp::Reverse(link.created));
+
+    #[derive(Serialize)]
+    struct ReturnData {
+        links: Vec<PublishedShareLink>,
+    }
+    let request = &ctx.request;
+    let resp = ReturnData {
+        links: links
+            .into_iter()
+            .map(|link| PublishedShareLink {
+                url: public_url(request, &format!("/share/{}", link.share_id)),
+                link,
+            })
+            .collect(),
+    };
+    Ok(Response::new(Body::from(serde_json::to_string(&resp)?)))
+}
+
+/// Lua script that removes a share link from its token and, only if it was
+/// one of the token's links, deletes the link itself and records the audit
+/// entry of the change.
+///
+/// KEYS: token shares hash, share link, audit log
+/// ARGV: share id, audit entry json
+static REVOKE_SHARE: &str = r#"
+if redis.call('HDEL', KEYS[1], ARGV[1]) == 1 then
+    redis.call('DEL', KEYS[2])
+    redis.call('RPUSH', KEYS[3], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// Build the command revoking `share_id` of `token` on behalf of `actor`,
+/// returns `1` if the link belonged to the token and was revoked
+fn revoke_share(token: &str, share_id: &str, actor: &Actor) -> Result<redis::Cmd> {
+    let mut cmd = redis::cmd("EVAL");
+    // share ids are bearer secrets, so only the token is recorded
+    cmd.arg(REVOKE_SHARE)
+        .arg(3)
+        .arg(format!("mpix.token_shares:{}", token))
+        .arg(format!("mpix.share:{}", share_id))
+        .arg(audit::AUDIT_LOG)
+        .arg(share_id)
+        .arg(audit::entry(actor, Action::ShareRevoke, token)?);
+    Ok(cmd)
+}
+
+/// Revoke a share link before it expires
+pub async fn revoke_share_link(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let share_id = ctx.captures.get("share_id")?;
+    let actor = Actor::new(&auth, &ctx.request);
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = ensure_access(conn, &auth, &token, Access::Manage).await?;
+    let (_, removed): (_, bool) = revoke_share(&token, &share_id, &actor)?
+        .query_async(conn)
+        .compat()
+        .await?;
+    if !removed {
+        Err(ErrorKind::DoesNotExist(format!(
+            "share link `{}` not found",
+            share_id
+        )))?
+    }
+
+    slog::debug!(LOG, "revoked share link"; "token" => &token);
+    let r = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+/// Public, unauthenticated view of the token behind a share link
+pub async fn shared_stats(ctx: Context) -> Result<Response<Body>> {
+    let share_id = ctx.captures.get("share_id")?;
+    let not_found = || ErrorKind::DoesNotExist("share link not found".into());
+    if !ShareLink::verify_id(&share_id) {
+        Err(not_found())?
+    }
+
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, link): (_, Option<ShareLink>) = redis::cmd("GET")
+        .arg(format!("mpix.share:{}", share_id))
+        .query_async(conn)
+        .compat()
+        .await?;
+    let link = link.ok_or_else(not_found)?;
+
+    let (conn, owner): (_, Option<String>) = redis::cmd("HGET")
+        .arg(TOKEN_REGISTRY)
+        .arg(&link.token)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let owner = owner.ok_or_else(not_found)?;
+    let (conn, token): (_, Option<Token>) = redis::cmd("HGET")
+        .arg(format!("mpix.user_tokens:{}", owner))
+        .arg(&link.token)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let token = token.ok_or_else(not_found)?;
+    let (conn, events) = token_events(conn, &token.token, &EventRange::default()).await?;
+    let (_, counters) = token_counters(conn, &token.token).await?;
+
+    #[derive(Serialize)]
+    struct ReturnData {
+        token: Token,
+        #[serde(serialize_with = "timezone::serialize")]
+        expires: chrono::DateTime<chrono::Utc>,
+        summary: Summary,
+        events: Vec<TokenData>,
+    }
+    let tz = timezone::from_request(&ctx.request)?;
+    let resp = ReturnData {
+        summary: Summary::new(&token, &events, counters, tz),
+        token,
+        expires: link.expires,
+        events,
+    };
+    let r = Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&resp, tz)?))?;
+    Ok(r)
+}
+
+/// Formats events can be exported as
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Columns of an exported event, in the order they're written
+const EXPORT_COLUMNS: &[&str] = &["token", "created", "ip", "user_agent"];
+
+/// A single exported event, the same schema is used for every format
+#[derive(Serialize)]
+struct ExportRow<'a> {
+    token: &'a str,
+    created: String,
+    ip: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+impl<'a> ExportRow<'a> {
+    fn new(token: &'a str, event: &'a TokenData, tz: Tz) -> Self {
+        Self {
+            token,
+            created: event.created.with_timezone(&tz).to_rfc3339(),
+            ip: event.ip.as_deref(),
+            user_agent: event.user_agent.as_deref(),
+        }
+    }
+}
+
+/// Format a token's events as a chunk of export output
+fn export_chunk(
+    format: ExportFormat,
+    token: &str,
+    events: &[TokenData],
+    tz: Tz,
+) -> Result<Vec<u8>> {
+    let rows = events.iter().map(|event| ExportRow::new(token, event, tz));
+    match format {
+        ExportFormat::Csv => {
+            let mut w = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(vec![]);
+            for row in rows {
+                w.serialize(row)?;
+            }
+            Ok(w.into_inner()
+                .map_err(|e| format!("error flushing csv writer {:?}", e))?)
+        }
+        ExportFormat::Ndjson => {
+            let mut buf = vec![];
+            for row in rows {
+                serde_json::to_writer(&mut buf, &row)?;
+                buf.push(b'\n');
+            }
+            Ok(buf)
+        }
+    }
+}
+
+/// Stream the events of `tokens` as a response body, one token at a time,
+/// so large exports are never held in memory at once
+fn export_body(
+    conn: RedisConnection,
+    tokens: Vec<String>,
+    range: EventRange,
+    format: ExportFormat,
+    tz: Tz,
+) -> Result<Body> {
+    let header = match format {
+        ExportFormat::Csv => {
+            let mut w = csv::Writer::from_writer(vec![]);
+            w.write_record(EXPORT_COLUMNS)?;
+            w.into_inner()
+                .map_err(|e| format!("error flushing csv writer {:?}", e))?
+        }
+        ExportFormat::Ndjson => vec![],
+    };
+    let chunks = futures::stream::unfold(
+        Some((conn, tokens.into_iter(), range)),
+        move |state| async move {
+            let (conn, mut tokens, range) = state?;
+            let token = tokens.next()?;
+            match token_events(conn, &token, &range).await {
+                Ok((conn, events)) => {
+                    let chunk = export_chunk(format, &token, &events, tz);
+                    Some((chunk, Some((conn, tokens, range))))
+                }
+                // end the stream after reporting the error
+                Err(e) => Some((Err(e), None)),
+            }
+        },
+    );
+    let body = futures::stream::once(futures::future::ready(Ok(header))).chain(chunks);
+    Ok(Body::wrap_stream(body.boxed().compat()))
+}
+
+fn export_response(format: ExportFormat, name: &str, body: Body) -> Result<Response<Body>> {
+    let r = Response::builder()
+        .header("content-type", format.content_type())
+        .header(
+            "content-disposition",
+            format!("attachment; filename=\"{}.{}\"", name, format.extension()),
+        )
+        .body(body)?;
+    Ok(r)
+}
+
+fn export_params(request: &Request<Body>) -> Result<(ExportFormat, EventRange, Tz)> {
+    let params: ExportQuery = query(request)?;
+    let range = EventRange::from_query(EventQuery {
+        since: params.since,
+        until: params.until,
+        limit: params.limit,
+    })?;
+    Ok((params.format, range, timezone::from_request(request)?))
+}
+
+/// Export a token's events as csv or ndjson
+pub async fn export_token(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let (format, range, tz) = export_params(&ctx.request)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = ensure_access(conn, &auth, &token, Access::Read).await?;
+    let name = format!("mpix-{}", token);
+    let body = export_body(conn, vec![token], range, format, tz)?;
+    export_response(format, &name, body)
+}
+
+/// Export the events of every token the user or their orgs can read as csv
+/// or ndjson
+pub async fn export_all(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let (format, range, tz) = export_params(&ctx.request)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let mut pipe = redis::pipe();
+    for namespace in auth.namespaces() {
+        pipe.cmd("HKEYS")
+            .arg(format!("mpix.user_tokens:{}", namespace));
+    }
+    if auth.personal {
+        pipe.cmd("HKEYS")
+            .arg(format!("mpix.user_shared:{}", auth.user_name));
+    }
+    let (conn, tokens): (_, Vec<Vec<String>>) = pipe.query_async(conn).compat().await?;
+    let mut tokens: Vec<String> = tokens.into_iter().flatten().collect();
+    tokens.sort();
+    tokens.dedup();
+    let body = export_body(conn, tokens, range, format, tz)?;
+    export_response(format, "mpix-export", body)
+}
+
+/// Load a token the caller has access to from its owner's tokens, along
+/// with the key of the hash it's saved in
+async fn load_token(
+    conn: RedisConnection,
+    auth: &Auth,
+    token: &str,
+) -> Result<(RedisConnection, String, Token)> {
+    let (conn, owner): (_, Option<String>) = redis::cmd("HGET")
+        .arg(TOKEN_REGISTRY)
+        .arg(token)
+        .query_async(conn)
+        .compat()
+        .await?;
+    // tokens from before the registry are only listed under their owner,
+    // which `ensure_access` has found to be the caller
+    let owner = owner.unwrap_or_else(|| auth.user_token.clone());
+    let key = format!("mpix.user_tokens:{}", owner);
+    let (conn, found): (_, Option<Token>) = redis::cmd("HGET")
+        .arg(&key)
+        .arg(token)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let found =
+        found.ok_or_else(|| ErrorKind::DoesNotExist(format!("token `{}` not found", token)))?;
+    Ok((conn, key, found))
+}
+
+/// Archive or restore a token, archived tokens keep their data but stop recording events
+async fn set_archived(ctx: Context, archived: bool) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let token = ctx.captures.get("token")?;
+    let actor = Actor::new(&auth, &ctx.request);
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = ensure_access(conn, &auth, &token, Access::Manage).await?;
+    let (conn, key, mut found) = load_token(conn, &auth, &token).await?;
+    found.archived = archived;
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HSET")
+        .arg(key)
+        .arg(&token)
+        .arg(serde_json::to_string(&found)?)
+        .ignore()
+        .cmd(if archived { "SADD" } else { "SREM" })
+        .arg(ARCHIVED_TOKENS)
+        .arg(&token)
+        .ignore();
+    let action = if archived {
+        Action::TokenArchive
+    } else {
+        Action::TokenUnarchive
+    };
+    audit::record(&mut pipe, &actor, action, &token)?;
+    let (_, ()) = pipe.query_async(conn).compat().await?;
+
+    slog::debug!(LOG, "set token archived"; "token" => &token, "archived" => archived);
+    let r = Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&found)?))?;
+    Ok(r)
+}
+
+pub async fn archive(ctx: Context) -> Result<Response<Body>> {
+    set_archived(ctx, true).await
+}
+
+pub async fn unarchive(ctx: Context) -> Result<Response<Body>> {
+    set_archived(ctx, false).await
+}
+
+/// Every token the user owns, their orgs own, or has been shared with
+async fn listed_tokens(
+    conn: RedisConnection,
+    auth: &Auth,
+    include_archived: bool,
+) -> Result<(RedisConnection, Vec<ListedToken>)> {
+    let mut owners = vec![];
+    if auth.personal {
+        owners.push((auth.user_token.clone(), None, Access::Owner));
+    }
+    for membership in &auth.orgs {
+        owners.push((
+            membership.namespace(),
+            Some(membership.org.clone()),
+            role_access(membership.role),
+        ));
+    }
+    let mut pipe = redis::pipe();
+    for (namespace, _, _) in &owners {
+        pipe.cmd("HVALS")
+            .arg(format!("mpix.user_tokens:{}", namespace));
+    }
+    let (conn, owned): (_, Vec<Vec<Token>>) = pipe.query_async(conn).compat().await?;
+    let (conn, shared) = shared_tokens(conn, auth).await?;
+    let tokens = owners
+        .into_iter()
+        .zip(owned)
+        .flat_map(|((_, org, access), tokens)| {
+            tokens.into_iter().map(move |token| ListedToken {
+                token,
+                access,
+                shared: false,
+                org: org.clone(),
+            })
+        })
+        .chain(shared)
+        .filter(|listed| include_archived || !listed.token.archived)
+        .collect();
+    Ok((conn, tokens))
+}
+
+pub async fn tracking_stats(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx
+        .auth
+        .ok_or_else(|| "in an authorized context without a token")?;
+    let tz = timezone::from_request(&ctx.request)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    match ctx.captures.get("token").ok() {
+        Some(token) => {
+            #[derive(Serialize)]
+            struct ReturnData {
+                events: Vec<TokenData>,
+                /// Pass as `after` to fetch only newer events
+                cursor: String,
+            }
+
+            let range = EventRange::from_query(query(&ctx.request)?)?;
+            let method = ctx.request.method();
+            let tail =
+                Tail::from_query(query(&ctx.request)?, &range)?.map(|tail| tail.for_method(method));
+            let (conn, _) = ensure_access(conn, &auth, &token, Access::Read).await?;
+            let (events, cursor) = match tail {
+                Some(tail) => {
+                    let (_, mut events) = tail_events(conn, &token, &tail, range.limit).await?;
+                    let cursor = tail.after.advance(&events);
+                    events.reverse();
+                    (events, cursor)
+                }
+                None => {
+                    let (_, events) = token_events(conn, &token, &range).await?;
+                    let cursor = Cursor::default().advance(events.iter().rev());
+                    (events, cursor)
+                }
+            };
+            let version = events_version(&token, &events);
+            let resp = ReturnData {
+                events,
+                cursor: cursor.to_string(),
+            };
+            let mut r = Response::new(Body::from(timezone::to_json(&resp, tz)?));
+            r.extensions_mut()
+                .insert(etag_source(&ctx.request, version)?);
+            Ok(r)
+        }
+        None => {
+            #[derive(Serialize)]
+            struct ReturnData {
+                tokens: Vec<ListedToken>,
+            }
+
+            #[derive(Deserialize)]
+            struct Params {
+                #[serde(default)]
+                include_archived: bool,
+            }
+            let params: Params = query(&ctx.request)?;
+
+            let (_, tokens) = listed_tokens(conn, &auth, params.include_archived).await?;
+            let resp = ReturnData { tokens };
+            // listings have no event counts, so they're versioned by content
+            let body = timezone::to_json(&resp, tz)?;
+            let version = format!("{}\n{}", auth.user_name, body);
+            let mut r = Response::new(Body::from(body));
+            r.extensions_mut()
+                .insert(etag_source(&ctx.request, version)?);
+            Ok(r)
+        }
+    }
+}
+/// What the caller has used of their quota, and of their org's when acting in one
+/// What the caller has used of their quota
+pub async fn usage(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let tz = timezone::from_request(&ctx.request)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (_, usage) = quotas::usage(conn, &auth).await?;
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&usage, tz)?))?)
+}
+
+#[derive(Serialize)]
+struct Status<'a, 'b> {
+    status: &'a str,
+    hash: &'b str,
+}
+
+pub async fn status(_ctx: Context) -> Result<Response<Body>> {
+    let st = Status {
+        status: "ok",
+        hash: include_str!("../commit_hash.txt").trim(),
+    };
+    let status = serde_json::to_string(&st)?;
+    Ok(Response::new(Body::from(status)))
+}
+
+/// Prometheus metrics, for allowlisted addresses or holders of the metrics token
+pub async fn metrics(ctx: Context) -> Result<Response<Body>> {
+    if !metrics::is_allowed(&ctx.request) {
+        Err(ErrorKind::Forbidden(
+            "metrics are not available to this client".into(),
+        ))?
+    }
+    metrics::render()
+}
+
+pub async fn not_found(_ctx: Context) -> Result<Response<Body>> {
+    let r = Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("not found"))?;
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_can_read_registered_token() {
+        assert!(is_owner("alice", Some("alice"), true));
+    }
+
+    #[test]
+    fn other_user_cannot_read_registered_token() {
+        assert!(!is_owner("mallory", Some("alice"), false));
+    }
+
+    #[test]
+    fn registry_wins_over_user_listing() {
+        assert!(!is_owner("mallory", Some("alice"), true));
+    }
+
+    #[test]
+    fn legacy_token_readable_by_lister_only() {
+        assert!(is_owner("alice", None, true));
+        assert!(!is_owner("mallory", None, false));
+    }
+
+    #[test]
+    fn org_tokens_are_reached_through_roles() {
+        use crate::orgs::Membership;
+        let member = |org: &str, role| Membership {
+            org: org.into(),
+            role,
+        };
+        let mut auth = Auth {
+            orgs: vec![
+                member("acme", Role::Member),
+                member("initech", Role::Viewer),
+            ],
+            ..Auth::admin()
+        };
+        auth.user_token = "jane-id".into();
+        auth.user_name = "jane".into();
+        let access = |auth: &Auth, owner: &str, granted: Option<&str>| {
+            caller_access(auth, Some(owner.into()), false, granted.map(String::from)).unwrap()
+        };
+        assert_eq!(access(&auth, "org:acme", None), Some(Access::Owner));
+        assert_eq!(access(&auth, "org:initech", None), Some(Access::Read));
+        assert_eq!(
+            access(&auth, "org:initech", Some("manage")),
+            Some(Access::Manage)
+        );
+        assert_eq!(access(&auth, "org:globex", None), None);
+        assert_eq!(access(&auth, "jane-id", None), Some(Access::Owner));
+
+        // keys issued for an org reach nothing else
+        auth.orgs.truncate(1);
+        auth.personal = false;
+        assert_eq!(access(&auth, "org:acme", None), Some(Access::Owner));
+        assert_eq!(access(&auth, "jane-id", None), None);
+        assert_eq!(access(&auth, "bob-id", Some("read")), None);
+        assert_eq!(auth.namespaces(), vec!["org:acme".to_string()]);
+    }
+
+    #[test]
+    fn viewers_cant_create_org_tokens() {
+        use crate::orgs::Membership;
+        let mut auth = Auth::admin();
+        assert_eq!(creation_namespace(&auth).unwrap(), auth.user_token);
+        auth.org = Some(Membership {
+            org: "acme".into(),
+            role: Role::Member,
+        });
+        assert_eq!(creation_namespace(&auth).unwrap(), "org:acme");
+        auth.org = Some(Membership {
+            org: "acme".into(),
+            role: Role::Viewer,
+        });
+        assert!(creation_namespace(&auth).is_err());
+    }
+
+    #[test]
+    fn timestamps_parse_from_rfc3339_and_epoch() {
+        let expected = parse_timestamp("1700000000").unwrap();
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20Z").unwrap(), expected);
+        // `+` offsets arrive as spaces after query string decoding
+        assert_eq!(
+            parse_timestamp("2023-11-14T23:13:20 01:00").unwrap(),
+            expected
+        );
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    fn event_range_rejects_inverted_windows() {
+        let q = EventQuery {
+            since: Some("1700000100".into()),
+            until: Some("1700000000".into()),
+            limit: None,
+        };
+        assert!(EventRange::from_query(q).is_err());
+        assert_eq!(
+            EventRange::from_query(EventQuery::default()).unwrap(),
+            EventRange::default()
+        );
+    }
+
+    fn event_at(rfc3339: &str, ip: Option<&str>) -> TokenData {
+        TokenData {
+            created: chrono::DateTime::parse_from_rfc3339(rfc3339)
+                .unwrap()
+                .with_timezone(&chrono::Utc),
+            ip: ip.map(String::from),
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn cursor_counts_events_sharing_a_score() {
+        let events = vec![
+            event_at("2023-11-14T22:13:19Z", None),
+            event_at("2023-11-14T22:13:20Z", Some("10.0.0.1")),
+            event_at("2023-11-14T22:13:20Z", Some("10.0.0.2")),
+        ];
+        let cursor = Cursor::default().advance(&events);
+        assert_eq!(cursor.to_string(), "1700000000000.2");
+        assert_eq!("1700000000000.2".parse::<Cursor>().unwrap(), cursor);
+        assert_eq!(cursor.advance(&[]), cursor);
+        assert_eq!(
+            cursor.advance(&events[2..]),
+            Cursor {
+                score: 1_700_000_000_000,
+                seen: 3
+            }
+        );
+        assert!("1700000000000".parse::<Cursor>().is_err());
+        assert!("latest.1".parse::<Cursor>().is_err());
+    }
+
+    /// An event as `RECORD_EVENT` stores it
+    fn recorded(seq: u64, event: &TokenData) -> String {
+        let json = serde_json::to_string(event).unwrap();
+        format!(r#"{{"seq":"{:020}",{}"#, seq, &json[1..])
+    }
+
+    #[test]
+    fn cursor_reads_events_sharing_a_score_in_recorded_order() {
+        // recorded in the opposite order of their plain json
+        let late = event_at("2023-11-14T22:13:20Z", Some("10.0.0.9"));
+        let later = event_at("2023-11-14T22:13:20Z", Some("10.0.0.1"));
+        let (first, second) = (recorded(9, &late), recorded(10, &later));
+        assert!(first < second);
+
+        // a reader that saw `late` skips only it once `later` is recorded
+        let cursor = Cursor::default().advance(&[late]);
+        let mut index = [second.clone(), first];
+        index.sort();
+        let after: Vec<TokenData> = index[cursor.seen..]
+            .iter()
+            .map(|raw| serde_json::from_str(raw).unwrap())
+            .collect();
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].ip.as_deref(), Some("10.0.0.1"));
+        assert_eq!(index[1], second);
+    }
+
+    #[test]
+    fn tail_requires_a_cursor_and_bounded_wait() {
+        let tail = |after: Option<&str>, wait: Option<u64>, range: &EventRange| {
+            let q = TailQuery {
+                after: after.map(String::from),
+                wait,
+            };
+            Tail::from_query(q, range)
+        };
+        let range = EventRange::default();
+        assert_eq!(tail(None, None, &range).unwrap(), None);
+        assert_eq!(
+            tail(Some("0.0"), Some(30), &range).unwrap().unwrap().wait,
+            std::time::Duration::from_secs(30)
+        );
+        assert!(tail(None, Some(30), &range).is_err());
+        assert!(tail(Some("0.0"), Some(MAX_TAIL_WAIT_SECS + 1), &range).is_err());
+        let since = EventRange {
+            since: Some(parse_timestamp("1700000000").unwrap()),
+            ..EventRange::default()
+        };
+        assert!(tail(Some("0.0"), None, &since).is_err());
+
+        let waiting = tail(Some("0.0"), Some(30), &range).unwrap().unwrap();
+        assert_eq!(
+            waiting.for_method(&Method::HEAD).wait,
+            std::time::Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn head_requests_dont_record_hits() {
+        let ctx = Context {
+            request: Request::head("/track/tok").body(Body::empty()).unwrap(),
+            captures: crate::Caps::with(
+                vec![("token".to_string(), "tok".to_string())]
+                    .into_iter()
+                    .collect(),
+            ),
+            auth: None,
+            // nothing listens here, so recording the hit would fail
+            redis: metrics::RedisClient::open("redis://127.0.0.1:1").unwrap(),
+        };
+        let resp = futures::executor::block_on(track(ctx)).unwrap();
+        assert_eq!(resp.headers()["content-type"], "image/png");
+    }
+
+    #[test]
+    fn summary_counts_events() {
+        let mut token = Token::new("summary");
+        token.created = event_at("2023-11-13T10:00:00Z", None).created;
+        let events = vec![
+            event_at("2023-11-14T12:00:00Z", Some("10.0.0.2")),
+            event_at("2023-11-14T11:00:00Z", Some("10.0.0.1")),
+            event_at("2023-11-13T11:00:00Z", Some("10.0.0.1")),
+        ];
+        let summary = Summary::new(&token, &events, Counters::default(), Tz::UTC);
+        assert_eq!(summary.total_opens, 3);
+        assert_eq!(summary.unique_opens, 2);
+        assert_eq!(summary.secs_to_first_open, Some(60 * 60));
+        assert_eq!(summary.last_open, Some(events[0].created));
+        assert_eq!(summary.opens_per_day.values().sum::<usize>(), 3);
+        assert_eq!(summary.hour_of_week.iter().flatten().sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn summary_prefers_counters_over_trimmed_events() {
+        let token = Token::new("summary");
+        let events = vec![event_at("2023-11-14T12:00:00Z", None)];
+        let first = event_at("2023-11-01T12:00:00Z", None).created;
+        let counters = Counters {
+            total_opens: 500,
+            unique_opens: 40,
+            first_open: Some(first),
+        };
+        let summary = Summary::new(&token, &events, counters, Tz::UTC);
+        assert_eq!(summary.total_opens, 500);
+        assert_eq!(summary.unique_opens, 40);
+        assert_eq!(summary.first_open, Some(first));
+    }
+
+    #[test]
+    fn export_rows_match_columns() {
+        let events = vec![event_at("2023-11-14T12:00:00Z", Some("10.0.0.1"))];
+        let csv = export_chunk(ExportFormat::Csv, "tok", &events, Tz::UTC).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.trim_end().split(',').count(), EXPORT_COLUMNS.len());
+        assert!(csv.starts_with("tok,"));
+
+        let ndjson = export_chunk(ExportFormat::Ndjson, "tok", &events, Tz::UTC).unwrap();
+        let row: HashMap<String, serde_json::Value> = serde_json::from_slice(&ndjson).unwrap();
+        let mut keys = row.keys().map(String::as_str).collect::<Vec<_>>();
+        let mut columns = EXPORT_COLUMNS.to_vec();
+        keys.sort();
+        columns.sort();
+        assert_eq!(keys, columns);
+    }
+
+    fn user(name: &str) -> Auth {
+        let mut auth = Auth::admin();
+        auth.user_token = format!("{}-id", name);
+        auth.user_name = name.into();
+        auth
+    }
+
+    /// What `ensure_access` makes of the values `access_cmds` read for `auth`
+    fn stat_access(
+        auth: &Auth,
+        registered_owner: Option<&str>,
+        listed: bool,
+        granted: Option<&str>,
+    ) -> Result<Access> {
+        let access = caller_access(
+            auth,
+            registered_owner.map(String::from),
+            listed,
+            granted.map(String::from),
+        )?;
+        require_access(access, "tok", Access::Read)
+    }
+
+    #[test]
+    fn other_users_tokens_are_not_found() {
+        let err = stat_access(&user("mallory"), Some("alice-id"), false, None)
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        // listing a registered token under yourself doesn't make it yours
+        let err = stat_access(&user("mallory"), Some("alice-id"), true, None)
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            stat_access(&user("alice"), Some("alice-id"), true, None).unwrap(),
+            Access::Owner
+        );
+    }
+
+    #[test]
+    fn legacy_tokens_are_only_found_by_their_lister() {
+        assert_eq!(
+            stat_access(&user("alice"), None, true, None).unwrap(),
+            Access::Owner
+        );
+        let err = stat_access(&user("mallory"), None, false, None)
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn shared_readers_cant_manage() {
+        let access = caller_access(
+            &user("bob"),
+            Some("alice-id".into()),
+            false,
+            Some("read".into()),
+        )
+        .unwrap();
+        assert_eq!(
+            require_access(access, "tok", Access::Read).unwrap(),
+            Access::Read
+        );
+        let err = require_access(access, "tok", Access::Manage).err().unwrap();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+    }
+
+    /// The arguments of a packed command, without its name
+    fn packed_args(cmd: &redis::Cmd) -> Vec<String> {
+        let packed = String::from_utf8(cmd.get_packed_command()).unwrap();
+        let lines: Vec<&str> = packed.split("\r\n").collect();
+        // `*n`, the name, then `$len` and the value of each argument and a final CRLF
+        lines[4..lines.len() - 1]
+            .iter()
+            .step_by(2)
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn revoking_another_tokens_share_link_leaves_it_alone() {
+        // a share id of `theirs` revoked through `mine`, which the caller manages
+        let actor = Actor {
+            name: "jane".into(),
+            key_id: None,
+            ip: None,
+            forwarded_for: None,
+        };
+        let args = packed_args(&revoke_share("mine", "theirs-share-id", &actor).unwrap());
+        assert_eq!(
+            args[..5],
+            [
+                REVOKE_SHARE,
+                "3",
+                "mpix.token_shares:mine",
+                "mpix.share:theirs-share-id",
+                audit::AUDIT_LOG,
+            ]
+        );
+        assert_eq!(args[5], "theirs-share-id");
+        // the link is only deleted when it was one of `mine`'s
+        let hdel = REVOKE_SHARE.find("HDEL").unwrap();
+        let del = REVOKE_SHARE.find("'DEL'").unwrap();
+        assert!(REVOKE_SHARE[hdel..del].contains("== 1 then"));
+    }
+
+    #[test]
+    fn claims_over_quota_are_forbidden() {
+        assert!(claimed(1, "tok").is_ok());
+        let err = claimed(-1, "tok").err().unwrap();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        let err = claimed(0, "tok").err().unwrap();
+        assert_eq!(err.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn org_tokens_count_against_the_org_quota() {
+        let mut auth = user("jane");
+        auth.orgs = vec![crate::orgs::Membership {
+            org: "acme".into(),
+            role: Role::Member,
+        }];
+        auth.org = auth.orgs.first().cloned();
+        let actor = Actor::anonymous("jane", &Request::new(Body::empty()));
+        let owner = creation_namespace(&auth).unwrap();
+        let args = packed_args(&claim_token(&owner, &Token::new("pixel"), &actor).unwrap());
+        // the quota the claim checks is the one admins set for the org
+        assert_eq!(args[5], quotas::QUOTAS);
+        assert_eq!(args[7], crate::orgs::namespace("acme"));
+
+        auth.org = None;
+        let owner = creation_namespace(&auth).unwrap();
+        let args = packed_args(&claim_token(&owner, &Token::new("pixel"), &actor).unwrap());
+        assert_eq!(args[7], "jane-id");
+    }
+
+    #[test]
+    fn owner_has_full_access() {
+        assert_eq!(
+            resolve_access("alice", Some("alice"), true, None),
+            Some(Access::Owner)
+        );
+    }
+
+    #[test]
+    fn shared_user_gets_granted_access() {
+        assert_eq!(
+            resolve_access("bob", Some("alice"), false, Some(Access::Read)),
+            Some(Access::Read)
+        );
+        assert!(Access::Read < Access::Manage);
+    }
+
+    #[test]
+    fn unshared_user_has_no_access() {
+        assert_eq!(resolve_access("mallory", Some("alice"), false, None), None);
+    }
+
+    #[test]
+    fn owner_access_cannot_be_granted() {
+        assert!("owner".parse::<Access>().is_err());
+        assert_eq!("manage".parse::<Access>().unwrap(), Access::Manage);
+    }
 }
@@ -0,0 +1,915 @@
+//! Admin api for managing users, their api keys and orgs, open to the
+//! `AUTH_TOKEN` credential and api keys with the `admin` scope.
+//!
+//! Users are stored by name in `mpix.accounts`, their hashed api keys in
+//! `mpix.api_keys`, and the prefixes of the keys issued to each user in
+//! `mpix.user_keys:{name}`. Users that were written into `mpix.users` by hand
+//! before accounts existed are adopted into accounts when the server starts.
+//! Users given a password can also sign in to the dashboard, their argon2
+//! hashes are kept apart from accounts in `mpix.passwords`.
+//!
+//! Quotas are set for users and for orgs, whose tokens and events count
+//! against the org rather than the member who created them.
+use super::{parse_timestamp, LOG};
+use crate::audit::{self, Action, Actor};
+use crate::auth_cache::{self, Invalidation};
+use crate::error::{ErrorKind, Result};
+use crate::keys::{self, API_KEYS, KEY_LAST_USED, PLAINTEXT_KEYS};
+use crate::metrics::RedisConnection;
+use crate::orgs::{self, Org, ORGS};
+use crate::quotas::{Quota, QUOTAS};
+use crate::sessions::{self, PASSWORDS};
+use crate::{crypto, timezone, Account, Context, Scope, User, ADMIN_NAME};
+use {
+    futures_util::{
+        compat::{Future01CompatExt, Stream01CompatExt},
+        TryStreamExt,
+    },
+    hyper::{Body, Response, StatusCode},
+    serde::{Deserialize, Serialize},
+    std::collections::{HashMap, HashSet},
+};
+
+/// Longest name a user can be created with
+const MAX_NAME_LEN: usize = 64;
+
+fn validate_name(name: &str) -> Result<()> {
+    lazy_static::lazy_static! {
+        // keep in sync with the `name` and `org` captures in `service::route`
+        static ref VALID: regex::Regex = regex::Regex::new(r"^[a-zA-Z0-9-_.@]+$").unwrap();
+    }
+    if name.len() > MAX_NAME_LEN {
+        Err(ErrorKind::BadRequest(format!(
+            "name must be at most {} characters",
+            MAX_NAME_LEN
+        )))?
+    }
+    if !VALID.is_match(name) {
+        Err(ErrorKind::BadRequest(format!(
+            "name `{}` must only contain [a-zA-Z0-9-_.@]",
+            name
+        )))?
+    }
+    if name == ADMIN_NAME {
+        Err(ErrorKind::BadRequest(format!(
+            "name `{}` is reserved",
+            name
+        )))?
+    }
+    Ok(())
+}
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4()
+        .to_simple()
+        .encode_lower(&mut uuid::Uuid::encode_buffer())
+        .to_string()
+}
+
+fn user_keys(name: &str) -> String {
+    format!("mpix.user_keys:{}", name)
+}
+
+/// The admin making a change
+fn actor(ctx: &Context) -> Result<Actor> {
+    let auth = ctx
+        .auth
+        .as_ref()
+        .ok_or("in an authorized context without a token")?;
+    Ok(Actor::new(auth, &ctx.request))
+}
+
+fn user_not_found(name: &str) -> ErrorKind {
+    ErrorKind::DoesNotExist(format!("user `{}` not found", name))
+}
+
+/// Adopt users that only have api keys into accounts, returns how many were
+/// adopted. Runs when the server starts, after plaintext keys are migrated so
+/// adopted users get their migrated namespace, see `service::run`.
+pub async fn adopt_legacy_users(conn: RedisConnection) -> Result<(RedisConnection, usize)> {
+    let mut pipe = redis::pipe();
+    pipe.cmd("HGETALL")
+        .arg(API_KEYS)
+        .cmd("HKEYS")
+        .arg("mpix.accounts");
+    type Stored = (HashMap<String, User>, HashSet<String>);
+    let (conn, (users, stored)): (_, Stored) = pipe.query_async(conn).compat().await?;
+
+    let mut pipe = redis::pipe();
+    let mut adopted = HashSet::new();
+    for (prefix, user) in users {
+        if stored.contains(&user.name) {
+            continue;
+        }
+        if !adopted.contains(&user.name) {
+            let account = Account {
+                id: user.id.unwrap_or_else(new_id),
+                name: user.name.clone(),
+                disabled: false,
+                created: chrono::Utc::now(),
+            };
+            pipe.cmd("HSETNX")
+                .arg("mpix.accounts")
+                .arg(&account.name)
+                .arg(serde_json::to_string(&account)?)
+                .ignore();
+            adopted.insert(account.name);
+        }
+        pipe.cmd("SADD")
+            .arg(user_keys(&user.name))
+            .arg(&prefix)
+            .ignore();
+    }
+    let conn = if adopted.is_empty() {
+        conn
+    } else {
+        let (conn, ()) = pipe.query_async(conn).compat().await?;
+        conn
+    };
+    Ok((conn, adopted.len()))
+}
+
+async fn accounts(conn: RedisConnection) -> Result<(RedisConnection, HashMap<String, Account>)> {
+    let (conn, accounts) = redis::cmd("HGETALL")
+        .arg("mpix.accounts")
+        .query_async(conn)
+        .compat()
+        .await?;
+    Ok((conn, accounts))
+}
+
+async fn account(conn: RedisConnection, name: &str) -> Result<(RedisConnection, Account)> {
+    let (conn, account): (_, Option<Account>) = redis::cmd("HGET")
+        .arg("mpix.accounts")
+        .arg(name)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let account = account.ok_or_else(|| user_not_found(name))?;
+    Ok((conn, account))
+}
+
+/// An account as returned by the admin api
+#[derive(Serialize)]
+struct ListedAccount {
+    #[serde(flatten)]
+    account: Account,
+    keys: usize,
+}
+
+pub async fn list_users(ctx: Context) -> Result<Response<Body>> {
+    let tz = timezone::from_request(&ctx.request)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, accounts) = accounts(conn).await?;
+    let mut accounts: Vec<Account> = accounts.into_values().collect();
+    accounts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut pipe = redis::pipe();
+    for account in &accounts {
+        pipe.cmd("SCARD").arg(user_keys(&account.name));
+    }
+    let keys: Vec<usize> = if accounts.is_empty() {
+        vec![]
+    } else {
+        let (_, keys) = pipe.query_async(conn).compat().await?;
+        keys
+    };
+    let users: Vec<ListedAccount> = accounts
+        .into_iter()
+        .zip(keys)
+        .map(|(account, keys)| ListedAccount { account, keys })
+        .collect();
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&users, tz)?))?)
+}
+
+#[derive(Deserialize)]
+struct CreateUser {
+    name: String,
+    /// Lets the user sign in to the dashboard, users have no password by default
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SetPassword {
+    password: String,
+}
+
+/// Longest name an api key can be issued under
+const MAX_KEY_NAME_LEN: usize = 64;
+
+fn validate_key_name(name: &str) -> Result<()> {
+    lazy_static::lazy_static! {
+        // keep in sync with the `key_name` capture in `service::route`
+        static ref VALID: regex::Regex = regex::Regex::new(r"^[a-zA-Z0-9-_.]+$").unwrap();
+    }
+    if name.len() > MAX_KEY_NAME_LEN || !VALID.is_match(name) {
+        Err(ErrorKind::BadRequest(format!(
+            "key name `{}` must be at most {} characters of [a-zA-Z0-9-_.]",
+            name, MAX_KEY_NAME_LEN
+        )))?
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct IssueKey {
+    name: String,
+    /// Defaults to `Scope::DEFAULT`
+    scopes: Option<Vec<Scope>>,
+    /// rfc3339 or epoch seconds, keys don't expire by default
+    expires: Option<String>,
+    /// Org the key can only act in, keys act as their user by default
+    org: Option<String>,
+}
+impl IssueKey {
+    fn into_key(self, account: &Account) -> Result<User> {
+        validate_key_name(&self.name)?;
+        let scopes = self.scopes.unwrap_or_else(|| Scope::DEFAULT.to_vec());
+        if scopes.is_empty() {
+            Err(ErrorKind::BadRequest("keys need at least one scope".into()))?
+        }
+        let now = chrono::Utc::now();
+        let expires = self.expires.as_deref().map(parse_timestamp).transpose()?;
+        if expires.map(|expires| expires <= now).unwrap_or(false) {
+            Err(ErrorKind::BadRequest(
+                "expires must be in the future".into(),
+            ))?
+        }
+        Ok(User {
+            name: account.name.clone(),
+            id: Some(account.id.clone()),
+            key_name: Some(self.name),
+            scopes: Some(scopes),
+            created: Some(now),
+            expires,
+            hash: None,
+            org: self.org,
+        })
+    }
+}
+
+/// An api key as shown by the admin api, which never shows the key itself
+#[derive(Serialize)]
+struct KeyInfo<'a> {
+    name: &'a str,
+    scopes: Vec<Scope>,
+    #[serde(serialize_with = "timezone::serialize_opt")]
+    created: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(serialize_with = "timezone::serialize_opt")]
+    expires: Option<chrono::DateTime<chrono::Utc>>,
+    expired: bool,
+    #[serde(serialize_with = "timezone::serialize_opt")]
+    last_used: Option<chrono::DateTime<chrono::Utc>>,
+}
+impl<'a> KeyInfo<'a> {
+    fn new(key: &'a User, last_used: Option<String>) -> Self {
+        Self {
+            name: key.key_name(),
+            scopes: key.scopes(),
+            created: key.created,
+            expires: key.expires,
+            expired: key.is_expired(),
+            last_used: last_used
+                .and_then(|ts| chrono::DateTime::parse_from_rfc3339(&ts).ok())
+                .map(|ts| ts.with_timezone(&chrono::Utc)),
+        }
+    }
+}
+
+/// A newly issued api key, only ever shown when it's issued
+#[derive(Serialize)]
+struct IssuedKey<'a> {
+    user: &'a str,
+    #[serde(flatten)]
+    key: KeyInfo<'a>,
+    api_key: String,
+}
+
+fn issued(key: &User, api_key: String) -> Result<Response<Body>> {
+    let issued = IssuedKey {
+        user: &key.name,
+        key: KeyInfo::new(key, None),
+        api_key,
+    };
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&issued)?))?)
+}
+
+/// The api keys issued to a user, and when each was last used
+async fn user_key_records(
+    conn: RedisConnection,
+    name: &str,
+) -> Result<(RedisConnection, Vec<(String, User, Option<String>)>)> {
+    let (conn, prefixes): (_, Vec<String>) = redis::cmd("SMEMBERS")
+        .arg(user_keys(name))
+        .query_async(conn)
+        .compat()
+        .await?;
+    if prefixes.is_empty() {
+        return Ok((conn, vec![]));
+    }
+    let mut pipe = redis::pipe();
+    pipe.cmd("HMGET")
+        .arg(API_KEYS)
+        .arg(prefixes.as_slice())
+        .cmd("HMGET")
+        .arg(KEY_LAST_USED)
+        .arg(prefixes.as_slice());
+    type Stored = (Vec<Option<User>>, Vec<Option<String>>);
+    let (conn, (records, last_used)): (_, Stored) = pipe.query_async(conn).compat().await?;
+    let records = prefixes
+        .into_iter()
+        .zip(records)
+        .zip(last_used)
+        .filter_map(|((prefix, record), last_used)| {
+            record.map(|record| (prefix, record, last_used))
+        })
+        .collect();
+    Ok((conn, records))
+}
+
+pub async fn create_user(ctx: Context) -> Result<Response<Body>> {
+    let actor = actor(&ctx)?;
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let create: CreateUser = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid create user input: {}", e)))?;
+    validate_name(&create.name)?;
+    let password = match create.password {
+        Some(password) => {
+            sessions::validate_password(&password)?;
+            Some(crypto::hash_password(password).await?)
+        }
+        None => None,
+    };
+
+    let account = Account {
+        name: create.name,
+        id: new_id(),
+        disabled: false,
+        created: chrono::Utc::now(),
+    };
+    let key = IssueKey {
+        name: "default".into(),
+        scopes: None,
+        expires: None,
+        org: None,
+    }
+    .into_key(&account)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let entry = audit::entry(&actor, Action::UserCreate, &account.name)?;
+    let (conn, created) = keys::issue_with_account(conn, &account, &key, &entry).await?;
+    let (_, api_key) = created
+        .ok_or_else(|| ErrorKind::Conflict(format!("user `{}` already exists", account.name)))?;
+    if let Some(password) = password {
+        let (_, ()) = redis::cmd("HSET")
+            .arg(PASSWORDS)
+            .arg(&account.name)
+            .arg(password)
+            .query_async(conn)
+            .compat()
+            .await?;
+    }
+
+    slog::info!(LOG, "created user"; "user" => &account.name);
+    issued(&key, api_key)
+}
+
+/// Set the password a user signs in to the dashboard with, ending the
+/// sessions started with the old password
+pub async fn set_password(ctx: Context) -> Result<Response<Body>> {
+    let name = ctx.captures.get("name")?;
+    let actor = actor(&ctx)?;
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let set: SetPassword = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid set password input: {}", e)))?;
+    sessions::validate_password(&set.password)?;
+    let hash = crypto::hash_password(set.password).await?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = account(conn, &name).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HSET")
+        .arg(PASSWORDS)
+        .arg(&name)
+        .arg(hash)
+        .ignore()
+        .add_command(sessions::end_all(&name));
+    audit::record(&mut pipe, &actor, Action::UserPassword, &name)?;
+    let (_, (ended,)): (_, (usize,)) = pipe.query_async(conn).compat().await?;
+
+    slog::info!(LOG, "set user password"; "user" => &name, "sessions_ended" => ended);
+    let r = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+/// The quota stored under `id`, a user's id or an org's namespace
+async fn stored_quota(conn: RedisConnection, id: &str) -> Result<Response<Body>> {
+    let (_, quota): (_, Option<Quota>) = redis::cmd("HGET")
+        .arg(QUOTAS)
+        .arg(id)
+        .query_async(conn)
+        .compat()
+        .await?;
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(
+            &quota.unwrap_or_default(),
+        )?))?)
+}
+
+async fn parse_quota(body: Body) -> Result<Quota> {
+    let body = body.compat().try_concat().await?;
+    Ok(serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid quota input: {}", e)))?)
+}
+
+/// Replace the quota stored under `id`, recording the audit entry of the change
+async fn replace_quota(
+    conn: RedisConnection,
+    id: &str,
+    quota: &Quota,
+    actor: &Actor,
+    action: Action,
+    target: &str,
+) -> Result<RedisConnection> {
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HSET")
+        .arg(QUOTAS)
+        .arg(id)
+        .arg(serde_json::to_string(quota)?)
+        .ignore();
+    audit::record(&mut pipe, actor, action, target)?;
+    let (conn, ()) = pipe.query_async(conn).compat().await?;
+    Ok(conn)
+}
+
+pub async fn get_quota(ctx: Context) -> Result<Response<Body>> {
+    let name = ctx.captures.get("name")?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, account) = account(conn, &name).await?;
+    stored_quota(conn, &account.id).await
+}
+
+/// Replace a user's quota, limits left out are unlimited
+pub async fn set_quota(ctx: Context) -> Result<Response<Body>> {
+    let name = ctx.captures.get("name")?;
+    let actor = actor(&ctx)?;
+    let quota = parse_quota(ctx.request.into_body()).await?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, account) = account(conn, &name).await?;
+    let conn = replace_quota(conn, &account.id, &quota, &actor, Action::UserQuota, &name).await?;
+    // cached credentials know whether their requests are limited
+    auth_cache::publish(conn, &Invalidation::User(name.clone())).await?;
+
+    slog::info!(LOG, "set user quota"; "user" => &name);
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&quota)?))?)
+}
+
+/// Make sure `name` is an org
+async fn ensure_org(conn: RedisConnection, name: &str) -> Result<RedisConnection> {
+    let (conn, exists): (_, bool) = redis::cmd("HEXISTS")
+        .arg(ORGS)
+        .arg(name)
+        .query_async(conn)
+        .compat()
+        .await?;
+    if !exists {
+        Err(ErrorKind::DoesNotExist(format!(
+            "organization `{}` not found",
+            name
+        )))?
+    }
+    Ok(conn)
+}
+
+pub async fn get_org_quota(ctx: Context) -> Result<Response<Body>> {
+    let org = ctx.captures.get("org")?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let conn = ensure_org(conn, &org).await?;
+    stored_quota(conn, &orgs::namespace(&org)).await
+}
+
+/// Replace an org's quota, which limits the tokens its members create for
+/// it and the events those record
+pub async fn set_org_quota(ctx: Context) -> Result<Response<Body>> {
+    let org = ctx.captures.get("org")?;
+    let actor = actor(&ctx)?;
+    let quota = parse_quota(ctx.request.into_body()).await?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let conn = ensure_org(conn, &org).await?;
+    let namespace = orgs::namespace(&org);
+    replace_quota(conn, &namespace, &quota, &actor, Action::OrgQuota, &org).await?;
+
+    slog::info!(LOG, "set org quota"; "org" => &org);
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&quota)?))?)
+}
+
+/// Issue another named api key to a user
+pub async fn issue_key(ctx: Context) -> Result<Response<Body>> {
+    let name = ctx.captures.get("name")?;
+    let actor = actor(&ctx)?;
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let issue: IssueKey = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid issue key input: {}", e)))?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, account) = account(conn, &name).await?;
+    let key = issue.into_key(&account)?;
+    let conn = match key.org.as_ref() {
+        Some(org) => {
+            let (conn, member): (_, bool) = redis::cmd("HEXISTS")
+                .arg(orgs::user_orgs_key(&name))
+                .arg(org)
+                .query_async(conn)
+                .compat()
+                .await?;
+            if !member {
+                Err(ErrorKind::BadRequest(format!(
+                    "user `{}` is not a member of organization `{}`",
+                    name, org
+                )))?
+            }
+            conn
+        }
+        None => conn,
+    };
+    let (conn, existing) = user_key_records(conn, &name).await?;
+    if existing
+        .iter()
+        .any(|(_, record, _)| record.key_name() == key.key_name())
+    {
+        Err(ErrorKind::Conflict(format!(
+            "user `{}` already has a key named `{}`",
+            name,
+            key.key_name()
+        )))?
+    }
+
+    let target = format!("{}/{}", name, key.key_name());
+    let entry = audit::entry(&actor, Action::KeyIssue, &target)?;
+    let (_, _, api_key) = keys::issue(conn, &key, &entry).await?;
+
+    slog::info!(LOG, "issued api key"; "user" => &name, "key" => key.key_name());
+    issued(&key, api_key)
+}
+
+pub async fn list_keys(ctx: Context) -> Result<Response<Body>> {
+    let tz = timezone::from_request(&ctx.request)?;
+    let name = ctx.captures.get("name")?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = account(conn, &name).await?;
+    let (_, records) = user_key_records(conn, &name).await?;
+    let mut keys: Vec<KeyInfo> = records
+        .iter()
+        .map(|(_, record, last_used)| KeyInfo::new(record, last_used.clone()))
+        .collect();
+    keys.sort_by(|a, b| a.name.cmp(b.name));
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&keys, tz)?))?)
+}
+
+/// Revoke a user's api key by name
+pub async fn revoke_key(ctx: Context) -> Result<Response<Body>> {
+    let name = ctx.captures.get("name")?;
+    let key_name = ctx.captures.get("key_name")?;
+    let actor = actor(&ctx)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = account(conn, &name).await?;
+    let (conn, records) = user_key_records(conn, &name).await?;
+    let revoked: Vec<String> = records
+        .iter()
+        .filter(|(_, record, _)| record.key_name() == key_name)
+        .map(|(prefix, _, _)| prefix.clone())
+        .collect();
+    if revoked.is_empty() {
+        Err(ErrorKind::DoesNotExist(format!(
+            "user `{}` has no key named `{}`",
+            name, key_name
+        )))?
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HDEL")
+        .arg(API_KEYS)
+        .arg(revoked.as_slice())
+        .ignore()
+        .cmd("HDEL")
+        .arg(KEY_LAST_USED)
+        .arg(revoked.as_slice())
+        .ignore()
+        .cmd("SREM")
+        .arg(user_keys(&name))
+        .arg(revoked.as_slice())
+        .ignore();
+    let target = format!("{}/{}", name, key_name);
+    audit::record(&mut pipe, &actor, Action::KeyRevoke, &target)?;
+    let (mut conn, ()) = pipe.query_async(conn).compat().await?;
+    for prefix in revoked {
+        conn = auth_cache::publish(conn, &Invalidation::Key(prefix)).await?;
+    }
+
+    slog::info!(LOG, "revoked api key"; "user" => &name, "key" => &key_name);
+    let r = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+async fn set_disabled(ctx: Context, disabled: bool) -> Result<Response<Body>> {
+    let tz = timezone::from_request(&ctx.request)?;
+    let name = ctx.captures.get("name")?;
+    let actor = actor(&ctx)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, mut account) = account(conn, &name).await?;
+    account.disabled = disabled;
+    let action = if disabled {
+        Action::UserDisable
+    } else {
+        Action::UserEnable
+    };
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HSET")
+        .arg("mpix.accounts")
+        .arg(&name)
+        .arg(serde_json::to_string(&account)?)
+        .ignore();
+    audit::record(&mut pipe, &actor, action, &name)?;
+    let (conn, ()) = pipe.query_async(conn).compat().await?;
+    if disabled {
+        auth_cache::publish(conn, &Invalidation::User(name.clone())).await?;
+    }
+
+    slog::info!(LOG, "set user disabled"; "user" => &name, "disabled" => disabled);
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&account, tz)?))?)
+}
+
+/// Stop a user's api keys from authorizing requests
+pub async fn disable_user(ctx: Context) -> Result<Response<Body>> {
+    set_disabled(ctx, true).await
+}
+
+pub async fn enable_user(ctx: Context) -> Result<Response<Body>> {
+    set_disabled(ctx, false).await
+}
+
+/// Delete a user, their api keys, their org memberships, and everything
+/// shared with them. Tokens they created stay claimed so nobody else can
+/// take them over.
+pub async fn delete_user(ctx: Context) -> Result<Response<Body>> {
+    let name = ctx.captures.get("name")?;
+    let actor = actor(&ctx)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = account(conn, &name).await?;
+
+    // orgs mustn't lose their last owner, and the transaction below fails
+    // if the user's memberships change after they're checked
+    let (conn, member_of, sole) = orgs::watch_memberships(conn, &name).await?;
+    if !sole.is_empty() {
+        Err(ErrorKind::BadRequest(format!(
+            "user `{}` is the only owner of organization `{}`",
+            name,
+            sole.join("`, `")
+        )))?
+    }
+    let shared_key = format!("mpix.user_shared:{}", name);
+    let orgs_key = orgs::user_orgs_key(&name);
+    let mut pipe = redis::pipe();
+    pipe.cmd("SMEMBERS")
+        .arg(user_keys(&name))
+        .cmd("HKEYS")
+        .arg(&shared_key);
+    let (conn, (keys, shared)): (_, (Vec<String>, Vec<String>)) =
+        pipe.query_async(conn).compat().await?;
+
+    // keys that haven't been migrated yet are still held in plaintext
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in &keys {
+        pipe.cmd("HDEL").arg(API_KEYS).arg(key).ignore();
+        pipe.cmd("HDEL").arg(PLAINTEXT_KEYS).arg(key).ignore();
+        pipe.cmd("HDEL").arg(KEY_LAST_USED).arg(key).ignore();
+    }
+    for token in &shared {
+        pipe.cmd("HDEL")
+            .arg(format!("mpix.token_access:{}", token))
+            .arg(&name)
+            .ignore();
+    }
+    for org in &member_of {
+        pipe.cmd("HDEL")
+            .arg(orgs::members_key(org))
+            .arg(&name)
+            .ignore();
+    }
+    pipe.cmd("DEL")
+        .arg(user_keys(&name))
+        .arg(&shared_key)
+        .arg(&orgs_key)
+        .ignore()
+        .cmd("HDEL")
+        .arg(PASSWORDS)
+        .arg(&name)
+        .ignore()
+        .cmd("HDEL")
+        .arg("mpix.accounts")
+        .arg(&name)
+        .ignore()
+        // a user created with the same name mustn't inherit the sessions
+        .add_command(sessions::end_all(&name))
+        .ignore();
+    audit::record(&mut pipe, &actor, Action::UserDelete, &name)?;
+    let (conn, done): (_, redis::Value) = pipe.query_async(conn).compat().await?;
+    if done == redis::Value::Nil {
+        Err(ErrorKind::Conflict(format!(
+            "organizations of user `{}` changed while deleting them, try again",
+            name
+        )))?
+    }
+    auth_cache::publish(conn, &Invalidation::User(name.clone())).await?;
+
+    slog::info!(LOG, "deleted user"; "user" => &name, "keys" => keys.len());
+    let r = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+#[derive(Deserialize)]
+struct CreateOrg {
+    name: String,
+    /// Existing user the org is created with as its first owner
+    owner: String,
+}
+
+/// An org as returned by the admin api
+#[derive(Serialize)]
+struct ListedOrg {
+    #[serde(flatten)]
+    org: Org,
+    members: usize,
+}
+
+pub async fn list_orgs(ctx: Context) -> Result<Response<Body>> {
+    let tz = timezone::from_request(&ctx.request)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, orgs): (_, HashMap<String, Org>) = redis::cmd("HGETALL")
+        .arg(ORGS)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let mut orgs: Vec<Org> = orgs.into_values().collect();
+    orgs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut pipe = redis::pipe();
+    for org in &orgs {
+        pipe.cmd("HLEN").arg(orgs::members_key(&org.name));
+    }
+    let members: Vec<usize> = if orgs.is_empty() {
+        vec![]
+    } else {
+        let (_, members) = pipe.query_async(conn).compat().await?;
+        members
+    };
+    let orgs: Vec<ListedOrg> = orgs
+        .into_iter()
+        .zip(members)
+        .map(|(org, members)| ListedOrg { org, members })
+        .collect();
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&orgs, tz)?))?)
+}
+
+/// Create an org, its owner manages its members from there on
+pub async fn create_org(ctx: Context) -> Result<Response<Body>> {
+    let tz = timezone::from_request(&ctx.request)?;
+    let actor = actor(&ctx)?;
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let create: CreateOrg = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid create org input: {}", e)))?;
+    validate_name(&create.name)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = account(conn, &create.owner).await?;
+    let org = Org {
+        name: create.name,
+        created: chrono::Utc::now(),
+    };
+    let entry = audit::entry(&actor, Action::OrgCreate, &org.name)?;
+    let (_, created) = orgs::create(conn, &org, &create.owner, &entry).await?;
+    if !created {
+        Err(ErrorKind::Conflict(format!(
+            "organization `{}` already exists",
+            org.name
+        )))?
+    }
+
+    slog::info!(LOG, "created org"; "org" => &org.name, "owner" => &create.owner);
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&org, tz)?))?)
+}
+
+/// Default and largest number of audit entries returned at once
+const DEFAULT_AUDIT_PAGE: u64 = 100;
+const MAX_AUDIT_PAGE: u64 = 1000;
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    /// Id of the oldest entry of the previous page, the newest entries are
+    /// returned first
+    before: Option<u64>,
+    limit: Option<u64>,
+}
+
+/// An audit entry as returned by the admin api
+#[derive(Serialize)]
+struct ListedEntry {
+    id: u64,
+    #[serde(flatten)]
+    entry: audit::Entry,
+}
+
+/// Page through the audit log, newest entries first
+pub async fn list_audit(ctx: Context) -> Result<Response<Body>> {
+    let tz = timezone::from_request(&ctx.request)?;
+    let params: AuditQuery = super::query(&ctx.request)?;
+    let limit = params.limit.unwrap_or(DEFAULT_AUDIT_PAGE);
+    if limit == 0 || limit > MAX_AUDIT_PAGE {
+        Err(ErrorKind::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_AUDIT_PAGE
+        )))?
+    }
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (_, page) = audit::page(conn, params.before, limit).await?;
+
+    #[derive(Serialize)]
+    struct ReturnData {
+        entries: Vec<ListedEntry>,
+        /// `before` of the next page, if there's one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next: Option<u64>,
+    }
+    let next = page.last().map(|(id, _)| *id).filter(|id| *id > 0);
+    let entries = page
+        .into_iter()
+        .map(|(id, entry)| ListedEntry { id, entry })
+        .collect();
+    let resp = ReturnData { entries, next };
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(timezone::to_json(&resp, tz)?))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_issued_with_default_scopes() {
+        let account = Account {
+            name: "jane".into(),
+            id: "jane-id".into(),
+            disabled: false,
+            created: chrono::Utc::now(),
+        };
+        let issue = |scopes: Option<Vec<Scope>>, expires: Option<&str>| IssueKey {
+            name: "crm-sync".into(),
+            scopes,
+            expires: expires.map(String::from),
+            org: None,
+        };
+        let key = issue(None, None).into_key(&account).unwrap();
+        assert_eq!(key.scopes(), Scope::DEFAULT);
+        assert_eq!(key.id.as_deref(), Some("jane-id"));
+        assert!(!key.is_expired());
+
+        let key = issue(Some(vec![Scope::StatsRead]), Some("4102444800"))
+            .into_key(&account)
+            .unwrap();
+        assert_eq!(key.scopes(), &[Scope::StatsRead]);
+        assert!(issue(Some(vec![]), None).into_key(&account).is_err());
+        assert!(issue(None, Some("1700000000")).into_key(&account).is_err());
+    }
+
+    #[test]
+    fn names_are_validated() {
+        assert!(validate_name("jane.doe@example.com").is_ok());
+        assert!(validate_name("jane doe").is_err());
+        assert!(validate_name(ADMIN_NAME).is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+}
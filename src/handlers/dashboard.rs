@@ -0,0 +1,474 @@
+//! Server rendered dashboard for browsing and creating tokens.
+//!
+//! Browsers can't attach an api key to page loads, so users sign in with their
+//! password at `/login`, which starts a session, see `sessions`. Dashboard
+//! routes are public and fall back to the sign in form when there's no signed
+//! in user. Forms carry the session's csrf token in a hidden field.
+use super::{
+    claim_token, claimed, ensure_access, listed_tokens, load_token, token_counters, token_events,
+    Access, CreateToken, EventRange, ListedToken, Summary, LOG,
+};
+use crate::audit::{self, Action, Actor};
+use crate::error::{ErrorKind, Result};
+use crate::metrics::RedisConnection;
+use crate::service::cookie;
+use crate::sessions::{self, SESSION_COOKIE};
+use crate::{timezone, Auth, Context, Scope};
+use {
+    chrono_tz::Tz,
+    futures_util::{
+        compat::{Future01CompatExt, Stream01CompatExt},
+        TryStreamExt,
+    },
+    hyper::{Body, Request, Response, StatusCode},
+    serde::Deserialize,
+    std::fmt::Write,
+};
+
+/// Number of days shown in a token's opens chart
+const CHART_DAYS: i64 = 30;
+
+static STYLE: &str = r#"
+body { font-family: sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; padding: .3em .6em; border-bottom: 1px solid #ddd; }
+form.inline { display: inline; }
+input { margin: .2em 0; }
+.error { color: #b00; }
+.muted { color: #777; }
+.chart rect { fill: #4a7ebb; }
+"#;
+
+/// Escape text for use in html content and attribute values
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Hidden form field with the csrf token of the signed in user's session
+fn csrf_field(auth: &Auth) -> String {
+    match auth.csrf_token.as_ref() {
+        Some(token) => format!(
+            r#"<input type="hidden" name="csrf_token" value="{}">"#,
+            escape(token)
+        ),
+        None => String::new(),
+    }
+}
+
+fn page(title: &str, auth: Option<&Auth>, content: &str) -> String {
+    let nav = match auth {
+        Some(auth) => format!(
+            r#"<nav><a href="/">tokens</a>
+<form class="inline" method="post" action="/logout">{}<button>sign out</button></form></nav>"#,
+            csrf_field(auth)
+        ),
+        None => String::new(),
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title} - mpix</title>
+<style>{style}</style>
+</head>
+<body>
+<h1>mpix</h1>
+{nav}
+{content}
+</body>
+</html>
+"#,
+        title = escape(title),
+        style = STYLE,
+        nav = nav,
+        content = content,
+    )
+}
+
+fn html(status: StatusCode, body: String) -> Result<Response<Body>> {
+    let r = Response::builder()
+        .status(status)
+        .header("content-type", "text/html; charset=utf-8")
+        .body(Body::from(body))?;
+    Ok(r)
+}
+
+fn redirect(location: &str) -> Result<Response<Body>> {
+    let r = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", location)
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+/// Redirect home, showing `error` above the page
+fn redirect_with_error(error: &str) -> Result<Response<Body>> {
+    let query = serde_urlencoded::to_string([("error", error)])
+        .map_err(|e| format!("error encoding redirect {:?}", e))?;
+    redirect(&format!("/?{}", query))
+}
+
+fn error_notice(error: Option<&str>) -> String {
+    error
+        .map(|e| format!(r#"<p class="error">{}</p>"#, escape(e)))
+        .unwrap_or_default()
+}
+
+fn login_page(error: Option<&str>) -> String {
+    let content = format!(
+        r#"{error}
+<form method="post" action="/login">
+<label>name <input name="name" autocomplete="username" autofocus required></label>
+<label>password <input type="password" name="password" autocomplete="current-password" required></label>
+<button>sign in</button>
+</form>"#,
+        error = error_notice(error),
+    );
+    page("sign in", None, &content)
+}
+
+/// Open counts of `tokens`, in the same order
+async fn open_counts(
+    conn: RedisConnection,
+    tokens: &[ListedToken],
+) -> Result<(RedisConnection, Vec<usize>)> {
+    if tokens.is_empty() {
+        return Ok((conn, vec![]));
+    }
+    let mut pipe = redis::pipe();
+    for listed in tokens {
+        pipe.cmd("GET")
+            .arg(format!("mpix.token_opens:{}", listed.token.token))
+            .cmd("LLEN")
+            .arg(format!("mpix.token:{}", listed.token.token));
+    }
+    let (conn, counts): (_, Vec<Option<usize>>) = pipe.query_async(conn).compat().await?;
+    let counts = counts
+        .chunks(2)
+        .map(|pair| pair.iter().map(|c| c.unwrap_or(0)).max().unwrap_or(0))
+        .collect();
+    Ok((conn, counts))
+}
+
+/// Sign in form, or the signed in user's tokens
+pub async fn index(ctx: Context) -> Result<Response<Body>> {
+    #[derive(Deserialize)]
+    struct Params {
+        error: Option<String>,
+    }
+    let params: Params = super::query(&ctx.request)?;
+    let auth = match ctx.auth.filter(|auth| auth.has_scope(Scope::StatsRead)) {
+        Some(auth) => auth,
+        None => return html(StatusCode::OK, login_page(params.error.as_deref())),
+    };
+    let tz = timezone::from_request(&ctx.request)?;
+
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, mut tokens) = listed_tokens(conn, &auth, true).await?;
+    tokens.sort_by_key(|listed| std::cmp::Reverse(listed.token.created));
+    let (_, counts) = open_counts(conn, &tokens).await?;
+
+    let mut rows = String::new();
+    for (listed, count) in tokens.iter().zip(counts) {
+        let token = &listed.token;
+        writeln!(
+            rows,
+            r#"<tr><td><a href="/dashboard/token/{token}">{token}</a></td><td>{description}</td><td>{labels}</td><td>{count}</td><td>{created}</td><td>{status}</td></tr>"#,
+            token = escape(&token.token),
+            description = escape(&token.description),
+            labels = escape(&token.labels.join(", ")),
+            count = count,
+            created = token.created.with_timezone(&tz).format("%Y-%m-%d %H:%M"),
+            status = match (listed.shared, token.archived) {
+                (_, true) => "archived",
+                (true, false) => listed.access.as_str(),
+                (false, false) => "",
+            },
+        )
+        .expect("writing to a string can't fail");
+    }
+    let content = format!(
+        r#"{error}
+<p class="muted">signed in as {user}</p>
+<h2>new token</h2>
+<form method="post" action="/dashboard/create">
+{csrf}
+<label>description <input name="description" required></label>
+<label>labels <input name="labels" placeholder="a; b"></label>
+<label>token <input name="token" placeholder="optional vanity id" pattern="[a-zA-Z0-9\-_]+"></label>
+<button>create</button>
+</form>
+<h2>tokens</h2>
+<table>
+<tr><th>token</th><th>description</th><th>labels</th><th>opens</th><th>created</th><th></th></tr>
+{rows}</table>"#,
+        error = error_notice(params.error.as_deref()),
+        user = escape(&auth.user_name),
+        csrf = csrf_field(&auth),
+        rows = rows,
+    );
+    html(StatusCode::OK, page("tokens", Some(&auth), &content))
+}
+
+/// Sign in with a password, starting a session
+pub async fn login(ctx: Context) -> Result<Response<Body>> {
+    #[derive(Deserialize)]
+    struct Form {
+        name: String,
+        password: String,
+    }
+    let (parts, body) = ctx.request.into_parts();
+    let request = Request::from_parts(parts, Body::empty());
+    let body = body.compat().try_concat().await?;
+    let form: Form = serde_urlencoded::from_bytes(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid login form: {}", e)))?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let address = Actor::anonymous(&form.name, &request).ip;
+    let (conn, allowed) =
+        sessions::take_login_attempt(conn, &form.name, address.as_deref()).await?;
+    if !allowed {
+        return html(
+            StatusCode::TOO_MANY_REQUESTS,
+            login_page(Some("too many sign in attempts, try again later")),
+        );
+    }
+    let (conn, account) = sessions::check_password(conn, &form.name, &form.password).await?;
+    let account = match account {
+        Some(account) => account,
+        None => {
+            return html(
+                StatusCode::UNAUTHORIZED,
+                login_page(Some("invalid name or password")),
+            )
+        }
+    };
+    let actor = Actor::anonymous(&account.name, &request);
+    let entry = audit::entry(&actor, Action::SessionLogin, &account.name)?;
+    let (_, session_id) = sessions::create(conn, &account, &entry).await?;
+    slog::info!(LOG, "signed in"; "user" => &account.name);
+    let r = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", "/")
+        .header("set-cookie", sessions::session_cookie(&session_id))
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+/// End the caller's session
+pub async fn logout(ctx: Context) -> Result<Response<Body>> {
+    if let Some(session_id) = cookie(&ctx.request, SESSION_COOKIE) {
+        let conn = ctx.redis.get_async_connection().compat().await?;
+        let entry = match ctx.auth.as_ref() {
+            Some(auth) => {
+                let actor = Actor::new(auth, &ctx.request);
+                Some(audit::entry(
+                    &actor,
+                    Action::SessionLogout,
+                    &auth.user_name,
+                )?)
+            }
+            None => None,
+        };
+        sessions::delete(conn, &session_id, entry.as_deref()).await?;
+    }
+    let r = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", "/")
+        .header("set-cookie", sessions::session_cookie(""))
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+/// Create a token from the dashboard form
+pub async fn create(ctx: Context) -> Result<Response<Body>> {
+    #[derive(Deserialize)]
+    struct Form {
+        description: String,
+        #[serde(default)]
+        labels: String,
+        #[serde(default)]
+        token: String,
+    }
+    let auth = match ctx.auth {
+        Some(auth) => auth,
+        None => return redirect("/"),
+    };
+    if !auth.has_scope(Scope::TokensCreate) {
+        return redirect_with_error("this api key can't create tokens");
+    }
+    let actor = Actor::new(&auth, &ctx.request);
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let form: Form = serde_urlencoded::from_bytes(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid create token form: {}", e)))?;
+    let args = CreateToken {
+        description: form.description,
+        labels: form
+            .labels
+            .split(';')
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .map(String::from)
+            .collect(),
+        token: Some(form.token.trim().to_string()).filter(|token| !token.is_empty()),
+    };
+    let token = match args.into_token() {
+        Ok(token) => token,
+        Err(e) => return redirect_with_error(&e.to_string()),
+    };
+
+    let namespace = auth.namespace();
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (_, claim): (_, i64) = claim_token(&namespace, &token, &actor)?
+        .query_async(conn)
+        .compat()
+        .await?;
+    if let Err(e) = claimed(claim, &token.token) {
+        return redirect_with_error(&e.to_string());
+    }
+    slog::debug!(LOG, "created token from dashboard"; "token" => &token.token);
+    redirect(&format!("/dashboard/token/{}", token.token))
+}
+
+/// Inline svg bar chart of the opens per day over the last `CHART_DAYS`
+fn opens_chart(summary: &Summary, tz: Tz) -> String {
+    const WIDTH: i64 = 600;
+    const HEIGHT: i64 = 120;
+    let bar = WIDTH / CHART_DAYS;
+    let today = chrono::Utc::now().with_timezone(&tz).date();
+    let days = (0..CHART_DAYS)
+        .rev()
+        .map(|ago| {
+            let day = (today - chrono::Duration::days(ago))
+                .format("%Y-%m-%d")
+                .to_string();
+            let count = summary.opens_per_day.get(&day).cloned().unwrap_or(0);
+            (day, count)
+        })
+        .collect::<Vec<_>>();
+    let max = days
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let mut bars = String::new();
+    for (i, (day, count)) in days.iter().enumerate() {
+        let height = (*count as i64 * HEIGHT) / max as i64;
+        write!(
+            bars,
+            r#"<rect x="{x}" y="{y}" width="{w}" height="{h}"><title>{day}: {count}</title></rect>"#,
+            x = i as i64 * bar,
+            y = HEIGHT - height,
+            w = bar - 2,
+            h = height,
+            day = day,
+            count = count,
+        )
+        .expect("writing to a string can't fail");
+    }
+    format!(
+        r#"<svg class="chart" xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">{bars}</svg>
+<p class="muted">opens per day, {first} to {last}, peak {max}</p>"#,
+        w = WIDTH,
+        h = HEIGHT,
+        bars = bars,
+        first = days.first().map(|(day, _)| day.as_str()).unwrap_or(""),
+        last = days.last().map(|(day, _)| day.as_str()).unwrap_or(""),
+        max = max,
+    )
+}
+
+/// A token's summary, opens chart, and recorded events
+pub async fn token(ctx: Context) -> Result<Response<Body>> {
+    let auth = match ctx.auth.filter(|auth| auth.has_scope(Scope::StatsRead)) {
+        Some(auth) => auth,
+        None => return redirect("/"),
+    };
+    let token = ctx.captures.get("token")?;
+    let tz = timezone::from_request(&ctx.request)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, _) = match ensure_access(conn, &auth, &token, Access::Read).await {
+        Ok(found) => found,
+        Err(e) if e.status() == StatusCode::NOT_FOUND => {
+            return html(
+                StatusCode::NOT_FOUND,
+                page("not found", Some(&auth), "<p>token not found</p>"),
+            )
+        }
+        Err(e) => return Err(e),
+    };
+    let (conn, _, found) = load_token(conn, &auth, &token).await?;
+    let (conn, events) = token_events(conn, &token, &EventRange::default()).await?;
+    let (_, counters) = token_counters(conn, &token).await?;
+    let summary = Summary::new(&found, &events, counters, tz);
+
+    let when = |ts: Option<chrono::DateTime<chrono::Utc>>| {
+        ts.map(|ts| {
+            ts.with_timezone(&tz)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| "-".into())
+    };
+    let mut rows = String::new();
+    for event in &events {
+        writeln!(
+            rows,
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            event.created.with_timezone(&tz).format("%Y-%m-%d %H:%M:%S"),
+            escape(event.ip.as_deref().unwrap_or("")),
+            escape(event.user_agent.as_deref().unwrap_or("")),
+        )
+        .expect("writing to a string can't fail");
+    }
+    let content = format!(
+        r#"<h2>{token}</h2>
+<p>{description}</p>
+<p class="muted">pixel: <code>/p/{token}</code>{archived}</p>
+<table>
+<tr><th>opens</th><th>unique opens</th><th>first open</th><th>last open</th></tr>
+<tr><td>{total}</td><td>{unique}</td><td>{first}</td><td>{last}</td></tr>
+</table>
+{chart}
+<h2>events</h2>
+<table>
+<tr><th>opened</th><th>ip</th><th>user agent</th></tr>
+{rows}</table>"#,
+        token = escape(&found.token),
+        description = escape(&found.description),
+        archived = if found.archived { " (archived)" } else { "" },
+        total = summary.total_opens,
+        unique = summary.unique_opens,
+        first = when(summary.first_open),
+        last = when(summary.last_open),
+        chart = opens_chart(&summary, tz),
+        rows = rows,
+    );
+    html(StatusCode::OK, page(&found.token, Some(&auth), &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(
+            escape(r#"<img src="x" onerror='y'>&"#),
+            "&lt;img src=&quot;x&quot; onerror=&#39;y&#39;&gt;&amp;"
+        );
+    }
+}
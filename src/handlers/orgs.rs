@@ -0,0 +1,184 @@
+//! Orgs as seen by their members, who list the orgs they're in and, as org
+//! admins and owners, manage who else is a member. Orgs themselves are
+//! created by admins, see `admin::create_org`.
+//!
+//! Only owners can make someone an owner or change what an owner can do, and
+//! every org keeps at least one owner. Members can always leave an org.
+use super::LOG;
+use crate::audit::{self, Action, Actor};
+use crate::auth_cache::{self, Invalidation};
+use crate::error::{ErrorKind, Result};
+use crate::orgs::{self, Membership, Role};
+use crate::{Auth, Context};
+use {
+    futures_util::{
+        compat::{Future01CompatExt, Stream01CompatExt},
+        TryStreamExt,
+    },
+    hyper::{Body, Response, StatusCode},
+    serde::{Deserialize, Serialize},
+};
+
+fn org_not_found(org: &str) -> ErrorKind {
+    ErrorKind::DoesNotExist(format!("organization `{}` not found", org))
+}
+
+/// The caller's role in `org`. Orgs the caller can't reach are reported as
+/// missing so their existence isn't leaked.
+fn caller_role(auth: &Auth, org: &str) -> Result<Role> {
+    auth.orgs
+        .iter()
+        .find(|membership| membership.org == org)
+        .map(|membership| membership.role)
+        .ok_or_else(|| org_not_found(org).into())
+}
+
+/// Make sure a member with role `caller` can change a member's role from
+/// `current` to `new`, where `None` is not being a member
+fn ensure_can_change(caller: Role, current: Option<Role>, new: Option<Role>) -> Result<()> {
+    if caller < Role::Admin {
+        Err(ErrorKind::Forbidden(
+            "only org admins and owners can manage members".into(),
+        ))?
+    }
+    if caller < Role::Owner && (current == Some(Role::Owner) || new == Some(Role::Owner)) {
+        Err(ErrorKind::Forbidden(
+            "only org owners can manage owners".into(),
+        ))?
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Member {
+    user: String,
+    role: Role,
+}
+
+/// The orgs the caller can reach and the one they're acting in
+pub async fn list_orgs(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+
+    #[derive(Serialize)]
+    struct ReturnData<'a> {
+        orgs: &'a [Membership],
+        active: Option<&'a str>,
+    }
+    let resp = ReturnData {
+        orgs: &auth.orgs,
+        active: auth.org.as_ref().map(|org| org.org.as_str()),
+    };
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&resp)?))?)
+}
+
+pub async fn list_members(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let org = ctx.captures.get("org")?;
+    caller_role(&auth, &org)?;
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (_, members) = orgs::members(conn, &org).await?;
+    let members = members.ok_or_else(|| org_not_found(&org))?;
+
+    #[derive(Serialize)]
+    struct ReturnData {
+        members: Vec<Member>,
+    }
+    let resp = ReturnData {
+        members: members
+            .into_iter()
+            .map(|(user, role)| Member { user, role })
+            .collect(),
+    };
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&resp)?))?)
+}
+
+/// Add a user to an org or change their role
+pub async fn set_member(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let org = ctx.captures.get("org")?;
+    let actor = Actor::new(&auth, &ctx.request);
+    let body = ctx.request.into_body().compat().try_concat().await?;
+    let member: Member = serde_json::from_slice(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid member input: {}", e)))?;
+    let caller = caller_role(&auth, &org)?;
+
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, members) = orgs::members(conn, &org).await?;
+    let members = members.ok_or_else(|| org_not_found(&org))?;
+    let current = members
+        .iter()
+        .find(|(name, _)| *name == member.user)
+        .map(|(_, role)| *role);
+    ensure_can_change(caller, current, Some(member.role))?;
+    let (conn, exists): (_, bool) = redis::cmd("HEXISTS")
+        .arg("mpix.accounts")
+        .arg(&member.user)
+        .query_async(conn)
+        .compat()
+        .await?;
+    if !exists {
+        Err(ErrorKind::DoesNotExist(format!(
+            "user `{}` not found",
+            member.user
+        )))?
+    }
+    let target = format!("{}/{}", org, member.user);
+    let entry = audit::entry(&actor, Action::OrgMemberSet, &target)?;
+    let conn = orgs::set_member(conn, &org, &member.user, member.role, &entry).await?;
+    auth_cache::publish(conn, &Invalidation::User(member.user.clone())).await?;
+
+    slog::info!(LOG, "set org member";
+                "org" => &org, "user" => &member.user, "role" => member.role.as_str());
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&member)?))?)
+}
+
+/// Remove a user from an org, or leave it
+pub async fn remove_member(ctx: Context) -> Result<Response<Body>> {
+    let auth = ctx.auth.ok_or("in an authorized context without a token")?;
+    let org = ctx.captures.get("org")?;
+    let user = ctx.captures.get("user")?;
+    let actor = Actor::new(&auth, &ctx.request);
+    let caller = caller_role(&auth, &org)?;
+
+    let conn = ctx.redis.get_async_connection().compat().await?;
+    let (conn, members) = orgs::members(conn, &org).await?;
+    let members = members.ok_or_else(|| org_not_found(&org))?;
+    let current = members
+        .iter()
+        .find(|(name, _)| *name == user)
+        .map(|(_, role)| *role);
+    if user != auth.user_name {
+        ensure_can_change(caller, current, None)?;
+    }
+    let target = format!("{}/{}", org, user);
+    let entry = audit::entry(&actor, Action::OrgMemberRemove, &target)?;
+    let conn = orgs::remove_member(conn, &org, &user, &entry).await?;
+    auth_cache::publish(conn, &Invalidation::User(user.clone())).await?;
+
+    slog::info!(LOG, "removed org member"; "org" => &org, "user" => &user);
+    let r = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?;
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_owners_manage_owners() {
+        assert!(ensure_can_change(Role::Member, None, Some(Role::Viewer)).is_err());
+        assert!(ensure_can_change(Role::Admin, None, Some(Role::Member)).is_ok());
+        assert!(ensure_can_change(Role::Admin, Some(Role::Viewer), Some(Role::Admin)).is_ok());
+        assert!(ensure_can_change(Role::Admin, None, Some(Role::Owner)).is_err());
+        assert!(ensure_can_change(Role::Admin, Some(Role::Owner), None).is_err());
+        assert!(ensure_can_change(Role::Owner, Some(Role::Owner), Some(Role::Admin)).is_ok());
+    }
+}
@@ -0,0 +1,427 @@
+//! Api keys are stored hashed. Each key is looked up by its first
+//! `PREFIX_LEN` characters in `mpix.api_keys`, and the stored record only
+//! holds an hmac of the key, keyed with `API_KEY_SECRET`, so a copy of redis
+//! can't be used to authenticate.
+//!
+//! Keys used to be stored in plaintext as the fields of `mpix.users`, and
+//! keys issued before users had ids were also the namespace of their user's
+//! data. `migrate_plaintext` moves those keys over, giving their users a new
+//! namespace, and keys that are still in plaintext are migrated the first
+//! time they're used. Legacy keys too short to have a prefix, or whose prefix
+//! is taken, are looked up by their hmac instead, see `hashed_id`, so every
+//! plaintext key can be migrated.
+use crate::audit::AUDIT_LOG;
+use crate::configuration::CONFIG;
+use crate::error::Result;
+use crate::metrics::RedisConnection;
+use crate::{crypto, Account, User, TOKEN_REGISTRY};
+use {futures::compat::Future01CompatExt, std::collections::HashMap};
+
+lazy_static::lazy_static! {
+    pub static ref LOG: slog::Logger = crate::LOG.new(slog::o!("mod" => "keys"));
+}
+
+/// Hashed api keys by prefix
+pub const API_KEYS: &str = "mpix.api_keys";
+
+/// Api keys stored before they were hashed, by key
+pub const PLAINTEXT_KEYS: &str = "mpix.users";
+
+/// When each api key last authorized a request, by prefix
+pub const KEY_LAST_USED: &str = "mpix.key_last_used";
+
+/// Number of leading characters of a key it's looked up by
+pub const PREFIX_LEN: usize = 8;
+
+/// Keys shorter than this would give too much of themselves away in their prefix
+const MIN_KEY_LEN: usize = 16;
+
+/// Attempts at issuing a key whose prefix isn't taken yet
+const ISSUE_ATTEMPTS: usize = 5;
+
+/// Lua script that stores a newly issued key, only if its prefix isn't taken
+/// yet, adds it to its user's keys and records the audit entry of the change.
+/// Given an account, the account is created along with the key, only if its
+/// name isn't taken yet. Returns `-1` if the name is taken, `0` if the prefix
+/// is and `1` once the key is stored.
+///
+/// KEYS: api keys, user keys set, accounts, audit log
+/// ARGV: prefix, hashed record json, user name, account json or '',
+///       audit entry json
+static ISSUE_KEY: &str = r#"
+if ARGV[4] ~= '' and redis.call('HEXISTS', KEYS[3], ARGV[3]) == 1 then
+    return -1
+end
+if redis.call('HSETNX', KEYS[1], ARGV[1], ARGV[2]) == 0 then
+    return 0
+end
+if ARGV[4] ~= '' then
+    redis.call('HSET', KEYS[3], ARGV[3], ARGV[4])
+end
+redis.call('SADD', KEYS[2], ARGV[1])
+redis.call('RPUSH', KEYS[4], ARGV[5])
+return 1
+"#;
+
+/// Lua script that moves a plaintext key over to its hashed record, stored
+/// under its prefix unless that's missing or taken, and under its hashed id
+/// otherwise. Users without an id get `new id` as the namespace of their
+/// data in place of the plaintext key. Returns what the key is stored under.
+///
+/// The plaintext key is only ever deleted or compared against, never written.
+///
+/// KEYS: api keys, plaintext keys, user keys set, last used, accounts,
+///       token registry, old user tokens hash, new user tokens hash
+/// ARGV: prefix or '', hashed record json, plaintext key, user name,
+///       new id or '', hashed id
+static MIGRATE_KEY: &str = r#"
+local id = ARGV[1]
+if id ~= '' and redis.call('HSETNX', KEYS[1], id, ARGV[2]) == 0 then
+    -- the prefix may already hold this very key, migrated before
+    if cjson.decode(redis.call('HGET', KEYS[1], id)).hash ~= ARGV[6] then
+        id = ''
+    end
+end
+if id == '' then
+    id = ARGV[6]
+    redis.call('HSET', KEYS[1], id, ARGV[2])
+end
+redis.call('HDEL', KEYS[2], ARGV[3])
+redis.call('SREM', KEYS[3], ARGV[3])
+redis.call('SADD', KEYS[3], id)
+redis.call('HDEL', KEYS[4], ARGV[3])
+if ARGV[5] ~= '' then
+    for _, token in ipairs(redis.call('HKEYS', KEYS[7])) do
+        if redis.call('HGET', KEYS[6], token) == ARGV[3] then
+            redis.call('HSET', KEYS[6], token, ARGV[5])
+        end
+    end
+    if redis.call('EXISTS', KEYS[7]) == 1 then
+        redis.call('RENAME', KEYS[7], KEYS[8])
+    end
+    local account = redis.call('HGET', KEYS[5], ARGV[4])
+    if account then
+        local decoded = cjson.decode(account)
+        if decoded.id == ARGV[3] then
+            decoded.id = ARGV[5]
+            redis.call('HSET', KEYS[5], ARGV[4], cjson.encode(decoded))
+        end
+    end
+end
+return id
+"#;
+
+/// The part of `key` it's looked up by, `None` for keys too short to hash
+pub fn prefix(key: &str) -> Option<&str> {
+    if key.len() >= MIN_KEY_LEN && key.is_char_boundary(PREFIX_LEN) {
+        Some(&key[..PREFIX_LEN])
+    } else {
+        None
+    }
+}
+
+/// What `key` is stored under when it has no prefix of its own, an hmac of
+/// the key that can't be recomputed without `secret`. It's also the hash
+/// its record holds, see `hashed`.
+fn hashed_id(key: &str, secret: &str) -> String {
+    crypto::sign(secret, key)
+}
+
+/// `record` as stored for `key`, with the key hashed with `secret`
+fn hashed(mut record: User, key: &str, secret: &str) -> User {
+    record.hash = Some(hashed_id(key, secret));
+    record
+}
+
+/// Whether `key` is the key `record` was stored for, compared in constant time
+fn matches(record: &User, key: &str, secret: &str) -> bool {
+    match record.hash.as_deref() {
+        Some(hash) => crypto::verify(secret, key, hash),
+        None => false,
+    }
+}
+
+fn new_key() -> String {
+    uuid::Uuid::new_v4()
+        .to_simple()
+        .encode_lower(&mut uuid::Uuid::encode_buffer())
+        .to_string()
+}
+
+fn user_keys(name: &str) -> String {
+    format!("mpix.user_keys:{}", name)
+}
+
+/// What `key` is stored under and its stored record, if it's a valid key.
+/// Keys are stored under their prefix, or their hashed id if they have none,
+/// and keys still stored in plaintext are migrated on the way.
+pub async fn lookup(
+    conn: RedisConnection,
+    key: &str,
+) -> Result<(RedisConnection, Option<(String, User)>)> {
+    let hashed_id = hashed_id(key, &CONFIG.key_secret);
+    let ids: Vec<String> = prefix(key)
+        .map(String::from)
+        .into_iter()
+        .chain(Some(hashed_id))
+        .collect();
+    let mut pipe = redis::pipe();
+    pipe.cmd("HMGET")
+        .arg(API_KEYS)
+        .arg(ids.as_slice())
+        .cmd("HGET")
+        .arg(PLAINTEXT_KEYS)
+        .arg(key);
+    let (conn, (records, plaintext)): (_, (Vec<Option<User>>, Option<User>)) =
+        pipe.query_async(conn).compat().await?;
+    let found = ids
+        .into_iter()
+        .zip(records)
+        .filter_map(|(id, record)| record.map(|record| (id, record)))
+        .find(|(_, record)| matches(record, key, &CONFIG.key_secret));
+    if let Some(found) = found {
+        return Ok((conn, Some(found)));
+    }
+    match plaintext {
+        Some(plaintext) => {
+            let (conn, migrated) = migrate_key(conn, key, plaintext).await?;
+            Ok((conn, Some(migrated)))
+        }
+        None => Ok((conn, None)),
+    }
+}
+
+/// Store `record` under a newly issued key, returning the key's prefix and
+/// the key itself. The key is never stored and can't be shown again. `entry`
+/// is the audit entry recorded along with the key.
+pub async fn issue(
+    conn: RedisConnection,
+    record: &User,
+    entry: &str,
+) -> Result<(RedisConnection, String, String)> {
+    let (conn, issued) = issue_for(conn, record, None, entry).await?;
+    let (prefix, key) = issued.ok_or("issued a key without creating its account")?;
+    Ok((conn, prefix, key))
+}
+
+/// Create `account` along with the first key of its user, see `issue`.
+/// Returns `None` if there already is an account by that name.
+pub async fn issue_with_account(
+    conn: RedisConnection,
+    account: &Account,
+    record: &User,
+    entry: &str,
+) -> Result<(RedisConnection, Option<(String, String)>)> {
+    issue_for(conn, record, Some(account), entry).await
+}
+
+async fn issue_for(
+    conn: RedisConnection,
+    record: &User,
+    account: Option<&Account>,
+    entry: &str,
+) -> Result<(RedisConnection, Option<(String, String)>)> {
+    let account = account.map(serde_json::to_string).transpose()?;
+    let mut conn = conn;
+    for _ in 0..ISSUE_ATTEMPTS {
+        let key = new_key();
+        let prefix = prefix(&key)
+            .expect("issued keys are long enough")
+            .to_string();
+        let stored = hashed(record.clone(), &key, &CONFIG.key_secret);
+        let (c, issued): (_, i64) = redis::cmd("EVAL")
+            .arg(ISSUE_KEY)
+            .arg(4)
+            .arg(API_KEYS)
+            .arg(user_keys(&record.name))
+            .arg("mpix.accounts")
+            .arg(AUDIT_LOG)
+            .arg(&prefix)
+            .arg(serde_json::to_string(&stored)?)
+            .arg(&record.name)
+            .arg(account.as_deref().unwrap_or(""))
+            .arg(entry)
+            .query_async(conn)
+            .compat()
+            .await?;
+        conn = c;
+        match issued {
+            -1 => return Ok((conn, None)),
+            1 => return Ok((conn, Some((prefix, key)))),
+            _ => (),
+        }
+    }
+    Err("unable to issue an api key with an unused prefix")?
+}
+
+/// The command moving plaintext `key`, stored for `record`, over to a hashed
+/// record, see `MIGRATE_KEY`. Users without an id are given `new_id`.
+fn migrate_cmd(key: &str, record: &User, new_id: Option<&str>, secret: &str) -> Result<redis::Cmd> {
+    let mut cmd = redis::cmd("EVAL");
+    cmd.arg(MIGRATE_KEY)
+        .arg(8)
+        .arg(API_KEYS)
+        .arg(PLAINTEXT_KEYS)
+        .arg(user_keys(&record.name))
+        .arg(KEY_LAST_USED)
+        .arg("mpix.accounts")
+        .arg(TOKEN_REGISTRY)
+        .arg(format!("mpix.user_tokens:{}", key))
+        .arg(format!(
+            "mpix.user_tokens:{}",
+            new_id.or(record.id.as_deref()).unwrap_or(key)
+        ))
+        .arg(prefix(key).unwrap_or(""))
+        .arg(serde_json::to_string(record)?)
+        .arg(key)
+        .arg(&record.name)
+        .arg(new_id.unwrap_or(""))
+        .arg(hashed_id(key, secret));
+    Ok(cmd)
+}
+
+/// Move a single plaintext key over to a hashed record, returning what the
+/// key is stored under and the record
+async fn migrate_key(
+    conn: RedisConnection,
+    key: &str,
+    record: User,
+) -> Result<(RedisConnection, (String, User))> {
+    let new_id = match record.id {
+        Some(_) => None,
+        None => Some(new_key()),
+    };
+    let mut record = record;
+    if let Some(new_id) = new_id.as_ref() {
+        record.id = Some(new_id.clone());
+    }
+    let record = hashed(record, key, &CONFIG.key_secret);
+    let (conn, id): (_, String) = migrate_cmd(key, &record, new_id.as_deref(), &CONFIG.key_secret)?
+        .query_async(conn)
+        .compat()
+        .await?;
+    slog::info!(LOG, "migrated plaintext api key";
+                "user" => &record.name, "by_prefix" => prefix(key) == Some(id.as_str()));
+    Ok((conn, (id, record)))
+}
+
+/// Migrate every api key still stored in plaintext, returning how many were migrated
+pub async fn migrate_plaintext(conn: RedisConnection) -> Result<(RedisConnection, usize)> {
+    let (mut conn, plaintext): (_, HashMap<String, User>) = redis::cmd("HGETALL")
+        .arg(PLAINTEXT_KEYS)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let migrated = plaintext.len();
+    for (key, record) in plaintext {
+        let (c, _) = migrate_key(conn, &key, record).await?;
+        conn = c;
+    }
+    Ok((conn, migrated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scope;
+
+    const SECRET: &str = "api-key-secret";
+
+    fn record() -> User {
+        User {
+            name: "jane".into(),
+            id: Some("jane-id".into()),
+            key_name: Some("default".into()),
+            scopes: Some(Scope::DEFAULT.to_vec()),
+            created: None,
+            expires: None,
+            hash: None,
+            org: None,
+        }
+    }
+
+    #[test]
+    fn keys_are_looked_up_by_prefix() {
+        let key = new_key();
+        assert_eq!(prefix(&key), Some(&key[..PREFIX_LEN]));
+        assert_eq!(prefix("short-key"), None);
+    }
+
+    #[test]
+    fn stored_records_authenticate_their_key() {
+        let key = new_key();
+        let stored = hashed(record(), &key, SECRET);
+        assert!(matches(&stored, &key, SECRET));
+        assert!(!matches(&stored, &new_key(), SECRET));
+        // records from before keys were hashed never match
+        assert!(!matches(&record(), &key, SECRET));
+    }
+
+    /// The keys and arguments of a packed `EVAL`, after the script itself
+    fn eval_args(cmd: &redis::Cmd) -> (Vec<String>, Vec<String>) {
+        let packed = String::from_utf8(cmd.get_packed_command()).unwrap();
+        let lines: Vec<&str> = packed.split("\r\n").collect();
+        // `*n`, the name, the script, then `$len` and the value of each argument
+        let args: Vec<String> = lines[6..lines.len() - 1]
+            .iter()
+            .step_by(2)
+            .map(|s| s.to_string())
+            .collect();
+        let numkeys: usize = args[0].parse().unwrap();
+        (args[1..=numkeys].to_vec(), args[numkeys + 1..].to_vec())
+    }
+
+    #[test]
+    fn migrated_snapshots_hold_no_plaintext_key() {
+        let mut legacy = record();
+        legacy.id = None;
+        for key in &[new_key(), "short-key".to_string()] {
+            let mut migrated = legacy.clone();
+            migrated.id = Some(new_key());
+            let stored = hashed(migrated, key, SECRET);
+            let new_id = stored.id.clone();
+            let (keys, args) =
+                eval_args(&migrate_cmd(key, &stored, new_id.as_deref(), SECRET).unwrap());
+            // the plaintext key is passed to be deleted, along with the
+            // namespace named after it
+            assert_eq!(args[2], *key);
+            assert_eq!(keys[6], format!("mpix.user_tokens:{}", key));
+            // everything written instead: the ids the key is stored under,
+            // its record, the namespace renamed to and registry values
+            let written = [&keys[7], &args[0], &args[1], &args[3], &args[4], &args[5]];
+            for value in &written {
+                assert!(!value.contains(key.as_str()), "{} holds the key", value);
+            }
+            assert_eq!(args[5], stored.hash.clone().unwrap());
+        }
+        // and the script never writes what it's passed as the plaintext key
+        for line in MIGRATE_KEY.lines().filter(|line| line.contains("ARGV[3]")) {
+            assert!(
+                line.contains("'HDEL'") || line.contains("'SREM'") || line.contains("=="),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn leaked_snapshot_cant_authenticate() {
+        let key = new_key();
+        let prefix = prefix(&key).unwrap().to_string();
+        let stored = serde_json::to_string(&hashed(record(), &key, SECRET)).unwrap();
+
+        // everything redis holds for the key: its prefix and stored record
+        assert!(!stored.contains(&key));
+        let leaked: User = serde_json::from_str(&stored).unwrap();
+        let hash = leaked.hash.clone().unwrap();
+        for candidate in &[prefix.as_str(), hash.as_str(), stored.as_str()] {
+            assert!(!matches(&leaked, candidate, SECRET));
+        }
+        // and the hash can't be recomputed for guesses without the secret
+        assert!(!matches(
+            &hashed(record(), &key, "guessed-secret"),
+            &key,
+            SECRET
+        ));
+    }
+}
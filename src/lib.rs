@@ -1,8 +1,17 @@
+pub mod audit;
+pub mod auth_cache;
 pub mod configuration;
+pub mod crypto;
 pub mod error;
 pub mod handlers;
+pub mod keys;
 pub mod macros;
+pub mod metrics;
+pub mod orgs;
+pub mod quotas;
 pub mod service;
+pub mod sessions;
+pub mod timezone;
 
 use {
     error::{Error, ErrorKind, Result},
@@ -10,7 +19,7 @@ use {
     lazy_static::lazy_static,
     serde::{Deserialize, Serialize},
     slog::Logger,
-    std::collections::HashMap,
+    std::{collections::HashMap, net::SocketAddr},
 };
 
 pub mod log {
@@ -65,9 +74,75 @@ impl Caps {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// What an api key is allowed to do
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Create and manage tokens
+    #[serde(rename = "tokens:create")]
+    TokensCreate,
+    /// Read the stats of tokens
+    #[serde(rename = "stats:read")]
+    StatsRead,
+    /// Manage users and their api keys
+    #[serde(rename = "admin")]
+    Admin,
+}
+impl Scope {
+    pub const ALL: &'static [Scope] = &[Scope::TokensCreate, Scope::StatsRead, Scope::Admin];
+
+    /// Scopes of keys that weren't issued any explicitly
+    pub const DEFAULT: &'static [Scope] = &[Scope::TokensCreate, Scope::StatsRead];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::TokensCreate => "tokens:create",
+            Scope::StatsRead => "stats:read",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// An api key, stored in `mpix.api_keys` by prefix, and the user it belongs to
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     name: String,
+    /// Namespace of the user's data, plaintext keys issued before users had
+    /// ids are their own namespace until they're migrated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// Keyed hash of the key, see `keys`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// Name of the key, unique among the user's keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_name: Option<String>,
+    /// Scopes of the key, keys from before scopes existed get the defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scopes: Option<Vec<Scope>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<chrono::DateTime<chrono::Utc>>,
+    /// Org the key was issued for, it can only act in that org
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    org: Option<String>,
+}
+impl User {
+    fn key_name(&self) -> &str {
+        self.key_name.as_deref().unwrap_or("default")
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        self.scopes
+            .clone()
+            .unwrap_or_else(|| Scope::DEFAULT.to_vec())
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires
+            .map(|expires| expires <= chrono::Utc::now())
+            .unwrap_or(false)
+    }
 }
 impl redis::FromRedisValue for User {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<User> {
@@ -82,19 +157,107 @@ impl redis::FromRedisValue for User {
     }
 }
 
+/// A registered user, stored in `mpix.accounts` by name
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Account {
+    pub name: String,
+    pub id: String,
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(serialize_with = "timezone::serialize")]
+    pub created: chrono::DateTime<chrono::Utc>,
+}
+impl redis::FromRedisValue for Account {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Account> {
+        match *v {
+            redis::Value::Data(ref bytes) => Ok(serde_json::from_slice(bytes)
+                .map_err(|_| (redis::ErrorKind::TypeError, "Invalid account json bytes"))?),
+            _ => Err((
+                redis::ErrorKind::TypeError,
+                "Response type not account compatible.",
+            ))?,
+        }
+    }
+}
+
+/// Name the admin credential acts as, users can't be created with it
+pub const ADMIN_NAME: &str = "admin";
+
+/// Global hash of every token to the namespace that owns it
+pub const TOKEN_REGISTRY: &str = "mpix.tokens";
+
+#[derive(Clone)]
 pub struct Auth {
+    /// Namespace of the caller's personal data
     pub user_token: String,
+    pub user_name: String,
+    /// Scopes of the api key the caller authorized with
+    pub scopes: Vec<Scope>,
+    /// Prefix of the api key the caller authorized with, if they used one
+    pub key_id: Option<String>,
+    /// Csrf token of the caller's session, callers using api keys have none
+    pub csrf_token: Option<String>,
+    /// Orgs the caller can reach, see `orgs`
+    pub orgs: Vec<orgs::Membership>,
+    /// Org the caller acts in, new tokens are created in its namespace
+    pub org: Option<orgs::Membership>,
+    /// Whether the caller can reach their personal data, keys issued for an
+    /// org can't
+    pub personal: bool,
+    /// Whether the caller's quota limits their requests per minute, see `quotas`
+    pub rate_limited: bool,
 }
+impl Auth {
+    /// The `AUTH_TOKEN` admin credential, which has every scope
+    pub fn admin() -> Self {
+        Self {
+            user_token: ADMIN_NAME.into(),
+            user_name: ADMIN_NAME.into(),
+            scopes: Scope::ALL.to_vec(),
+            key_id: None,
+            csrf_token: None,
+            orgs: vec![],
+            org: None,
+            personal: true,
+            rate_limited: false,
+        }
+    }
+
+    /// Namespace new tokens of the caller are created in
+    pub fn namespace(&self) -> String {
+        match self.org.as_ref() {
+            Some(org) => org.namespace(),
+            None => self.user_token.clone(),
+        }
+    }
+
+    /// Every namespace the caller can reach
+    pub fn namespaces(&self) -> Vec<String> {
+        let personal = Some(self.user_token.clone()).filter(|_| self.personal);
+        personal
+            .into_iter()
+            .chain(self.orgs.iter().map(orgs::Membership::namespace))
+            .collect()
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// Address of the peer a request came in on, set on every request by `service::run`
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
 
 pub struct Context {
     request: Request<Body>,
     captures: Caps,
     auth: Option<Auth>,
-    redis: redis::Client,
+    redis: metrics::RedisClient,
 }
 impl Context {
-    fn redis() -> Result<redis::Client> {
-        Ok(redis::Client::open(
+    fn redis() -> Result<metrics::RedisClient> {
+        Ok(metrics::RedisClient::open(
             configuration::CONFIG.redis_url.as_ref(),
         )?)
     }
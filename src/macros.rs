@@ -8,25 +8,39 @@ macro_rules! router {
         // incoming request info
         $request:expr, $auth:expr, $method:expr, $uri:expr,
 
-        // a set of cases to match the incoming request info
-        // note: the last statement must be a catch all, `_ -> func`
+        // a set of cases to match the incoming request info, each with the
+        // `Option<Scope>` its api key needs, `None` for public routes
+        // note: the last statement must be a catch all, `_ -> func`, which
+        // is only called for paths no case matches. `HEAD` requests are
+        // served by `GET` cases, and paths that match with another method
+        // answer `OPTIONS` and `405`s with the methods they allow.
+        // see `service::allowed_methods`
         // ex.
         // ```
-        // [Method::GET, "^/status$", {}] -> handlers::status,
-        // [Method::POST, r"^/give/(?P<name>[\w]+)/(?P<number>[0-9]+)/dollars$", {"name", "number"}] -> handlers::transfer_money,
-        // [Method::GET, r"^/account/(?P<name>[\w]+)$", {"name"}] -> handlers::account,
+        // [Method::GET, "^/status$", {}, None] -> handlers::status,
+        // [Method::POST, r"^/give/(?P<name>[\w]+)/(?P<number>[0-9]+)/dollars$", {"name", "number"}, Some(Scope::TokensCreate)] -> handlers::transfer_money,
+        // [Method::GET, r"^/account/(?P<name>[\w]+)$", {"name"}, Some(Scope::StatsRead)] -> handlers::account,
         // _ -> handlers::not_found,
         // ```
-        $([$match_method:expr, $match_regex:expr, {$($match_capture_name:expr),*}] -> $match_func:expr),*
+        $([$match_method:expr, $match_regex:expr, {$($match_capture_name:expr),*}, $match_scope:expr] -> $match_func:expr),*
         , _ -> $no_match_func:expr
         $(,),*
     ) => {
+        // methods of the cases matching the path, and the first of those cases
+        let mut allowed: Vec<hyper::Method> = vec![];
+        let mut allowed_route: Option<&'static str> = None;
         $(
             {
                 lazy_static::lazy_static! {
                     static ref REG: regex::Regex = regex::Regex::new($match_regex).unwrap();
                 }
-                if $method == $match_method {
+                let serves_method = $method == $match_method
+                    || ($method == hyper::Method::HEAD && $match_method == hyper::Method::GET);
+                if !serves_method && REG.is_match($uri) {
+                    allowed.push($match_method);
+                    allowed_route.get_or_insert($match_regex);
+                }
+                if serves_method {
                     if let Some(caps) = REG.captures($uri) {
                         slog::debug!(
                             LOG,
@@ -61,12 +75,30 @@ macro_rules! router {
                         } else {
                             crate::Caps::empty()
                         };
-                        let ctx = crate::Context::new($request, $auth, url_captures)?;
-                        return Ok($match_func(ctx).await?);
+                        let resp = match crate::service::ensure_auth($auth, $match_scope) {
+                            Ok(auth) => {
+                                let ctx = crate::Context::new($request, auth, url_captures)?;
+                                $match_func(ctx).await
+                            }
+                            Err(err) => Err(err),
+                        };
+                        // errors are rendered here so the response
+                        // still records the route that produced it
+                        let mut resp = match resp {
+                            Ok(resp) => resp,
+                            Err(err) => crate::service::error_response(err)?,
+                        };
+                        resp.extensions_mut().insert(crate::metrics::Route($match_regex));
+                        return Ok(resp);
                     }
                 }
             }
         )*
+        if let Some(route) = allowed_route {
+            let mut resp = crate::service::allowed_methods(&$method, &allowed)?;
+            resp.extensions_mut().insert(crate::metrics::Route(route));
+            return Ok(resp);
+        }
         let ctx = crate::Context::with_req($request)?;
         return Ok($no_match_func(ctx).await?);
     };
@@ -0,0 +1,211 @@
+//! Prometheus metrics, rendered in the text exposition format at `/metrics`.
+//!
+//! Redis timings come from `RedisClient`, a drop in for `redis::Client` whose
+//! connections time each round trip, so a pipeline is observed once.
+use crate::configuration::CONFIG;
+use crate::{crypto, RemoteAddr};
+use {
+    futures01::Future,
+    hyper::{Body, Request, Response, StatusCode},
+    prometheus::{
+        Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+        TextEncoder,
+    },
+    std::time::{Duration, Instant},
+};
+
+lazy_static::lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref HTTP_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("mpix_http_requests_total", "Requests served, by route and status"),
+        &["route", "status"],
+    ));
+    pub static ref HTTP_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("mpix_http_request_duration_seconds", "Request latency, by route and status"),
+        &["route", "status"],
+    ));
+    pub static ref PIXEL_HITS_RECORDED: IntCounter = register(IntCounter::new(
+        "mpix_pixel_hits_recorded_total", "Pixel hits recorded as token events",
+    ));
+    pub static ref PIXEL_HITS_DROPPED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("mpix_pixel_hits_dropped_total", "Pixel hits served without being recorded"),
+        &["reason"],
+    ));
+    pub static ref REDIS_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("mpix_redis_request_duration_seconds", "Redis round trip latency, pipelines count once"),
+        &["kind"],
+    ));
+    pub static ref REDIS_ERRORS: IntCounter = register(IntCounter::new(
+        "mpix_redis_errors_total", "Redis requests that failed",
+    ));
+    pub static ref GZIP_BYTES_SAVED: IntCounter = register(IntCounter::new(
+        "mpix_gzip_bytes_saved_total", "Bytes saved by gzipping response bodies",
+    ));
+    pub static ref AUTH_CACHE_HITS: IntCounter = register(IntCounter::new(
+        "mpix_auth_cache_hits_total", "Api keys authenticated from the in-process cache",
+    ));
+    pub static ref AUTH_CACHE_MISSES: IntCounter = register(IntCounter::new(
+        "mpix_auth_cache_misses_total", "Api keys that had to be checked against redis",
+    ));
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("invalid metric");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// The route a response was served by, set by `router!`
+#[derive(Clone, Copy)]
+pub struct Route(pub &'static str);
+
+/// Label for the route a response was served by, e.g. `/stat/{token}`.
+/// Routes are labelled by their pattern to keep label cardinality bounded.
+pub fn route_label(resp: &Response<Body>) -> String {
+    lazy_static::lazy_static! {
+        static ref CAPTURE: regex::Regex = regex::Regex::new(r"\(\?P<(\w+)>[^)]*\)").unwrap();
+    }
+    match resp.extensions().get::<Route>() {
+        Some(Route(pattern)) => {
+            let path = pattern.trim_start_matches('^').trim_end_matches('$');
+            let label = CAPTURE.replace_all(path, "{$1}");
+            if label.is_empty() {
+                "/".to_string()
+            } else {
+                label.into_owned()
+            }
+        }
+        None => "unmatched".to_string(),
+    }
+}
+
+pub fn observe_request(resp: &Response<Body>, elapsed: Duration) {
+    let route = route_label(resp);
+    let status = resp.status().as_u16().to_string();
+    HTTP_REQUESTS.with_label_values(&[&route, &status]).inc();
+    HTTP_DURATION
+        .with_label_values(&[&route, &status])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Whether a request may read metrics, either by carrying the `METRICS_TOKEN`
+/// as a bearer token or by coming from an address `METRICS_ALLOWLIST` opts in.
+pub fn is_allowed(req: &Request<Body>) -> bool {
+    let from_allowed_addr = req
+        .extensions()
+        .get::<RemoteAddr>()
+        .map(|RemoteAddr(addr)| CONFIG.metrics_allowlist.contains(&addr.ip()))
+        .unwrap_or(false);
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|hv| hv.strip_prefix("Bearer "));
+    let has_token = match (CONFIG.metrics_token.as_ref(), bearer) {
+        (Some(token), Some(bearer)) => crypto::constant_time_eq(token, bearer.trim()),
+        _ => false,
+    };
+    from_allowed_addr || has_token
+}
+
+/// Render all metrics in the prometheus text format
+pub fn render() -> crate::error::Result<Response<Body>> {
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    encoder
+        .encode(&REGISTRY.gather(), &mut buf)
+        .map_err(|e| format!("error encoding metrics {:?}", e))?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", encoder.format_type())
+        .body(Body::from(buf))?)
+}
+
+/// `redis::Client` handing out connections that record metrics
+#[derive(Clone)]
+pub struct RedisClient(redis::Client);
+impl RedisClient {
+    pub fn open(url: &str) -> redis::RedisResult<Self> {
+        Ok(Self(redis::Client::open(url)?))
+    }
+
+    pub fn get_async_connection(
+        &self,
+    ) -> impl Future<Item = RedisConnection, Error = redis::RedisError> {
+        self.0.get_async_connection().map(RedisConnection)
+    }
+}
+
+/// `redis::aio::Connection` that records the latency and errors of requests
+pub struct RedisConnection(redis::aio::Connection);
+
+fn observe_redis<T>(
+    kind: &'static str,
+    start: Instant,
+    res: redis::RedisResult<(redis::aio::Connection, T)>,
+) -> redis::RedisResult<(RedisConnection, T)> {
+    REDIS_DURATION
+        .with_label_values(&[kind])
+        .observe(start.elapsed().as_secs_f64());
+    if res.is_err() {
+        REDIS_ERRORS.inc();
+    }
+    res.map(|(conn, value)| (RedisConnection(conn), value))
+}
+
+impl redis::aio::ConnectionLike for RedisConnection {
+    fn req_packed_command(self, cmd: Vec<u8>) -> redis::RedisFuture<(Self, redis::Value)> {
+        let start = Instant::now();
+        Box::new(
+            self.0
+                .req_packed_command(cmd)
+                .then(move |res| observe_redis("command", start, res)),
+        )
+    }
+
+    fn req_packed_commands(
+        self,
+        cmd: Vec<u8>,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<(Self, Vec<redis::Value>)> {
+        let start = Instant::now();
+        Box::new(
+            self.0
+                .req_packed_commands(cmd, offset, count)
+                .then(move |res| observe_redis("pipeline", start, res)),
+        )
+    }
+
+    fn get_db(&self) -> i64 {
+        self.0.get_db()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_are_labelled_by_pattern() {
+        let label = |route: Option<&'static str>| {
+            let mut resp = Response::new(Body::empty());
+            if let Some(route) = route {
+                resp.extensions_mut().insert(Route(route));
+            }
+            route_label(&resp)
+        };
+        assert_eq!(label(Some(r"^/status$")), "/status");
+        assert_eq!(label(Some(r"^$")), "/");
+        assert_eq!(
+            label(Some(
+                r"^/token/(?P<token>[a-zA-Z0-9-_]+)/share/(?P<share_id>[a-zA-Z0-9-_.]+)$"
+            )),
+            "/token/{token}/share/{share_id}"
+        );
+        assert_eq!(label(None), "unmatched");
+    }
+}
@@ -0,0 +1,490 @@
+//! Organizations own tokens on behalf of a team. Each org has a namespace of
+//! its own, `org:{name}`, whose tokens are kept in `mpix.user_tokens:org:{name}`
+//! next to the personal namespaces of users, and its members reach them
+//! according to their role.
+//!
+//! Members are stored both ways round, by user in `mpix.org_members:{org}` and
+//! by org in `mpix.user_orgs:{user}`, so credentials can pick up every org of
+//! their user in one read. Callers act in one of their orgs by naming it in the
+//! `x-mpix-org` header, and api keys issued for an org always act in it and
+//! reach nothing else.
+use crate::audit::AUDIT_LOG;
+use crate::error::{Error, ErrorKind, Result};
+use crate::metrics::RedisConnection;
+use crate::{timezone, Auth};
+use {
+    futures::compat::Future01CompatExt,
+    hyper::{Body, Request},
+    serde::{Deserialize, Serialize},
+    std::collections::HashMap,
+};
+
+/// Every org by name
+pub const ORGS: &str = "mpix.orgs";
+
+/// Header naming the org a request acts in
+pub const ORG_HEADER: &str = "x-mpix-org";
+
+/// Lua script that creates an org, only if its name isn't taken yet, with
+/// its first owner, and records the audit entry of its creation.
+///
+/// KEYS: orgs, org members hash, owner's orgs hash, audit log
+/// ARGV: org name, org json, owner name, audit entry json
+static CREATE_ORG: &str = r#"
+if redis.call('HSETNX', KEYS[1], ARGV[1], ARGV[2]) == 0 then
+    return 0
+end
+redis.call('HSET', KEYS[2], ARGV[3], 'owner')
+redis.call('HSET', KEYS[3], ARGV[1], 'owner')
+redis.call('RPUSH', KEYS[4], ARGV[4])
+return 1
+"#;
+
+/// Lua script that adds a member to an org or changes their role, unless
+/// that leaves the org without an owner, and records the audit entry of the
+/// change. Returns `0` if the org would lose its last owner, see `changed`.
+///
+/// KEYS: org members hash, member's orgs hash, audit log
+/// ARGV: user name, org name, role, audit entry json
+static SET_MEMBER: &str = r#"
+if ARGV[3] ~= 'owner' and redis.call('HGET', KEYS[1], ARGV[1]) == 'owner' then
+    local owners = 0
+    for _, role in ipairs(redis.call('HVALS', KEYS[1])) do
+        if role == 'owner' then
+            owners = owners + 1
+        end
+    end
+    if owners < 2 then
+        return 0
+    end
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+redis.call('HSET', KEYS[2], ARGV[2], ARGV[3])
+redis.call('RPUSH', KEYS[3], ARGV[4])
+return 1
+"#;
+
+/// Lua script that removes a member from an org, unless they're its last
+/// owner, and records the audit entry of the change. Returns `-1` if they
+/// aren't a member and `0` if they're the last owner, see `changed`.
+///
+/// KEYS: org members hash, member's orgs hash, audit log
+/// ARGV: user name, org name, audit entry json
+static REMOVE_MEMBER: &str = r#"
+local role = redis.call('HGET', KEYS[1], ARGV[1])
+if not role then
+    return -1
+end
+if role == 'owner' then
+    local owners = 0
+    for _, role in ipairs(redis.call('HVALS', KEYS[1])) do
+        if role == 'owner' then
+            owners = owners + 1
+        end
+    end
+    if owners < 2 then
+        return 0
+    end
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+redis.call('HDEL', KEYS[2], ARGV[2])
+redis.call('RPUSH', KEYS[3], ARGV[3])
+return 1
+"#;
+
+/// What a member of an org can do, from least to most privileged
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Read the stats of the org's tokens
+    Viewer,
+    /// Create and manage the org's tokens
+    Member,
+    /// Manage the org's tokens and who's a member
+    Admin,
+    /// Manage everything, including who else is an owner
+    Owner,
+}
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Member => "member",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+}
+impl std::str::FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "viewer" => Role::Viewer,
+            "member" => Role::Member,
+            "admin" => Role::Admin,
+            "owner" => Role::Owner,
+            s => Err(format!("Invalid role: {}", s))?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Org {
+    pub name: String,
+    #[serde(serialize_with = "timezone::serialize")]
+    pub created: chrono::DateTime<chrono::Utc>,
+}
+impl redis::FromRedisValue for Org {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Org> {
+        match *v {
+            redis::Value::Data(ref bytes) => Ok(serde_json::from_slice(bytes)
+                .map_err(|_| (redis::ErrorKind::TypeError, "Invalid org json bytes"))?),
+            _ => Err((
+                redis::ErrorKind::TypeError,
+                "Response type not org compatible.",
+            ))?,
+        }
+    }
+}
+
+/// An org a user is a member of and their role in it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Membership {
+    pub org: String,
+    pub role: Role,
+}
+impl Membership {
+    pub fn namespace(&self) -> String {
+        namespace(&self.org)
+    }
+}
+
+/// Namespace of the tokens owned by `org`
+pub fn namespace(org: &str) -> String {
+    format!("org:{}", org)
+}
+
+pub fn members_key(org: &str) -> String {
+    format!("mpix.org_members:{}", org)
+}
+
+pub fn user_orgs_key(user: &str) -> String {
+    format!("mpix.user_orgs:{}", user)
+}
+
+fn parse_roles(stored: HashMap<String, String>) -> Result<Vec<(String, Role)>> {
+    let mut roles = stored
+        .into_iter()
+        .map(|(name, role)| Ok((name, role.parse::<Role>()?)))
+        .collect::<Result<Vec<_>>>()?;
+    roles.sort();
+    Ok(roles)
+}
+
+/// The org a request asks to act in
+pub fn requested(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(ORG_HEADER)
+        .and_then(|hv| hv.to_str().ok())
+        .map(|org| org.trim().to_string())
+        .filter(|org| !org.is_empty())
+}
+
+/// The orgs credentials can reach and the one they act in. Keys issued for
+/// an org, `bound`, only reach that org, everybody else reaches all of their
+/// orgs and acts in the `requested` one, if any.
+fn choose(
+    memberships: Vec<Membership>,
+    requested: Option<&str>,
+    bound: Option<&str>,
+) -> Result<(Vec<Membership>, Option<Membership>)> {
+    let find = |org: &str| memberships.iter().find(|m| m.org == org).cloned();
+    match (bound, requested) {
+        (Some(bound), Some(requested)) if bound != requested => Err(ErrorKind::Forbidden(
+            format!("api key can only act in organization `{}`", bound),
+        ))?,
+        (Some(bound), _) => match find(bound) {
+            Some(membership) => Ok((vec![membership.clone()], Some(membership))),
+            None => Err(ErrorKind::InvalidAuth(format!(
+                "api key's user is no longer a member of organization `{}`",
+                bound
+            )))?,
+        },
+        (None, Some(requested)) => match find(requested) {
+            Some(membership) => Ok((memberships, Some(membership))),
+            None => Err(ErrorKind::Forbidden(format!(
+                "not a member of organization `{}`",
+                requested
+            )))?,
+        },
+        (None, None) => Ok((memberships, None)),
+    }
+}
+
+/// Load the orgs of the user `auth` is for and pick the one it acts in, see
+/// `choose`
+pub async fn activate(
+    conn: RedisConnection,
+    mut auth: Auth,
+    requested: Option<&str>,
+    bound: Option<&str>,
+) -> Result<(RedisConnection, Auth)> {
+    let (conn, stored): (_, HashMap<String, String>) = redis::cmd("HGETALL")
+        .arg(user_orgs_key(&auth.user_name))
+        .query_async(conn)
+        .compat()
+        .await?;
+    let memberships = parse_roles(stored)?
+        .into_iter()
+        .map(|(org, role)| Membership { org, role })
+        .collect();
+    let (orgs, org) = choose(memberships, requested, bound)?;
+    auth.personal = bound.is_none();
+    auth.orgs = orgs;
+    auth.org = org;
+    Ok((conn, auth))
+}
+
+/// Create `org` with `owner` as its first owner, recording the audit `entry`,
+/// returns whether the name was still free
+pub async fn create(
+    conn: RedisConnection,
+    org: &Org,
+    owner: &str,
+    entry: &str,
+) -> Result<(RedisConnection, bool)> {
+    let (conn, created): (_, bool) = redis::cmd("EVAL")
+        .arg(CREATE_ORG)
+        .arg(4)
+        .arg(ORGS)
+        .arg(members_key(&org.name))
+        .arg(user_orgs_key(owner))
+        .arg(AUDIT_LOG)
+        .arg(&org.name)
+        .arg(serde_json::to_string(org)?)
+        .arg(owner)
+        .arg(entry)
+        .query_async(conn)
+        .compat()
+        .await?;
+    Ok((conn, created))
+}
+
+/// The members of `org` by name with their roles, `None` if there's no such org
+pub async fn members(
+    conn: RedisConnection,
+    org: &str,
+) -> Result<(RedisConnection, Option<Vec<(String, Role)>>)> {
+    let mut pipe = redis::pipe();
+    pipe.cmd("HEXISTS")
+        .arg(ORGS)
+        .arg(org)
+        .cmd("HGETALL")
+        .arg(members_key(org));
+    let (conn, (exists, stored)): (_, (bool, HashMap<String, String>)) =
+        pipe.query_async(conn).compat().await?;
+    if !exists {
+        return Ok((conn, None));
+    }
+    Ok((conn, Some(parse_roles(stored)?)))
+}
+
+/// Whether `user` is the only owner among `members`, who can't leave their
+/// org or stop being its owner
+pub fn only_owner(members: &[(String, Role)], user: &str) -> bool {
+    let owners: Vec<&str> = members
+        .iter()
+        .filter(|(_, role)| *role == Role::Owner)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    owners == [user]
+}
+
+/// Turn what `SET_MEMBER` and `REMOVE_MEMBER` return into an error when
+/// nothing was changed
+fn changed(result: i64, org: &str, user: &str) -> Result<()> {
+    match result {
+        -1 => Err(ErrorKind::DoesNotExist(format!(
+            "user `{}` is not a member of organization `{}`",
+            user, org
+        )))?,
+        0 => Err(ErrorKind::BadRequest(
+            "organizations need at least one owner".into(),
+        ))?,
+        _ => Ok(()),
+    }
+}
+
+/// Add `user` to `org` or change their role, recording the audit `entry`.
+/// Fails rather than leave the org without an owner.
+pub async fn set_member(
+    conn: RedisConnection,
+    org: &str,
+    user: &str,
+    role: Role,
+    entry: &str,
+) -> Result<RedisConnection> {
+    let (conn, result): (_, i64) = redis::cmd("EVAL")
+        .arg(SET_MEMBER)
+        .arg(3)
+        .arg(members_key(org))
+        .arg(user_orgs_key(user))
+        .arg(AUDIT_LOG)
+        .arg(user)
+        .arg(org)
+        .arg(role.as_str())
+        .arg(entry)
+        .query_async(conn)
+        .compat()
+        .await?;
+    changed(result, org, user)?;
+    Ok(conn)
+}
+
+/// Remove `user` from `org`, recording the audit `entry`. Fails if they
+/// aren't a member or are its last owner.
+pub async fn remove_member(
+    conn: RedisConnection,
+    org: &str,
+    user: &str,
+    entry: &str,
+) -> Result<RedisConnection> {
+    let (conn, result): (_, i64) = redis::cmd("EVAL")
+        .arg(REMOVE_MEMBER)
+        .arg(3)
+        .arg(members_key(org))
+        .arg(user_orgs_key(user))
+        .arg(AUDIT_LOG)
+        .arg(user)
+        .arg(org)
+        .arg(entry)
+        .query_async(conn)
+        .compat()
+        .await?;
+    changed(result, org, user)?;
+    Ok(conn)
+}
+
+/// The orgs `user` is a member of and those among them they're the only
+/// owner of. Every key read is WATCHed, so a transaction that follows on
+/// `conn` fails if their memberships change in between.
+pub async fn watch_memberships(
+    conn: RedisConnection,
+    user: &str,
+) -> Result<(RedisConnection, Vec<String>, Vec<String>)> {
+    let orgs_key = user_orgs_key(user);
+    let (conn, ()) = redis::cmd("WATCH")
+        .arg(&orgs_key)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let (conn, stored): (_, HashMap<String, String>) = redis::cmd("HGETALL")
+        .arg(&orgs_key)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let member_of = parse_roles(stored)?;
+    let owned: Vec<&String> = member_of
+        .iter()
+        .filter(|(_, role)| *role == Role::Owner)
+        .map(|(org, _)| org)
+        .collect();
+    let mut conn = conn;
+    let mut sole = vec![];
+    for org in owned {
+        let key = members_key(org);
+        let (c, ()) = redis::cmd("WATCH")
+            .arg(&key)
+            .query_async(conn)
+            .compat()
+            .await?;
+        let (c, stored): (_, HashMap<String, String>) = redis::cmd("HGETALL")
+            .arg(&key)
+            .query_async(c)
+            .compat()
+            .await?;
+        conn = c;
+        if only_owner(&parse_roles(stored)?, user) {
+            sole.push(org.clone());
+        }
+    }
+    let member_of = member_of.into_iter().map(|(org, _)| org).collect();
+    Ok((conn, member_of, sole))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memberships() -> Vec<Membership> {
+        vec![
+            Membership {
+                org: "acme".into(),
+                role: Role::Admin,
+            },
+            Membership {
+                org: "initech".into(),
+                role: Role::Viewer,
+            },
+        ]
+    }
+
+    #[test]
+    fn roles_are_ordered_by_privilege() {
+        assert!(Role::Viewer < Role::Member);
+        assert!(Role::Member < Role::Admin);
+        assert!(Role::Admin < Role::Owner);
+        assert_eq!("admin".parse::<Role>().unwrap(), Role::Admin);
+        assert!("superuser".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn orgs_keep_an_owner() {
+        let members = vec![
+            ("jane".to_string(), Role::Owner),
+            ("joe".to_string(), Role::Admin),
+        ];
+        assert!(only_owner(&members, "jane"));
+        assert!(!only_owner(&members, "joe"));
+        let mut members = members;
+        members.push(("jim".to_string(), Role::Owner));
+        assert!(!only_owner(&members, "jane"));
+
+        let err = changed(0, "acme", "jane").err().unwrap();
+        assert_eq!(err.status(), hyper::StatusCode::BAD_REQUEST);
+        let err = changed(-1, "acme", "jim").err().unwrap();
+        assert_eq!(err.status(), hyper::StatusCode::NOT_FOUND);
+        assert!(changed(1, "acme", "jane").is_ok());
+    }
+
+    #[test]
+    fn callers_act_in_the_org_they_ask_for() {
+        let (orgs, org) = choose(memberships(), None, None).unwrap();
+        assert_eq!(orgs.len(), 2);
+        assert_eq!(org, None);
+
+        let (orgs, org) = choose(memberships(), Some("initech"), None).unwrap();
+        assert_eq!(orgs.len(), 2);
+        assert_eq!(org.unwrap().role, Role::Viewer);
+
+        let err = choose(memberships(), Some("globex"), None).err().unwrap();
+        assert_eq!(err.status(), hyper::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn bound_keys_only_reach_their_org() {
+        let (orgs, org) = choose(memberships(), None, Some("acme")).unwrap();
+        assert_eq!(orgs, vec![org.clone().unwrap()]);
+        assert_eq!(org.unwrap().namespace(), "org:acme");
+
+        assert!(choose(memberships(), Some("acme"), Some("acme")).is_ok());
+        let err = choose(memberships(), Some("initech"), Some("acme"))
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), hyper::StatusCode::FORBIDDEN);
+        // keys outlive their user's membership
+        let err = choose(memberships(), None, Some("globex")).err().unwrap();
+        assert_eq!(err.status(), hyper::StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,295 @@
+//! Per-user and per-org limits on tokens, recorded events and api requests,
+//! set by admins. Users and orgs without a quota, or without one of its
+//! limits, are unlimited.
+//!
+//! Quotas are stored by user id in `mpix.quotas`, and those of orgs by their
+//! namespace, `org:{name}`. Events are counted per utc day and requests per
+//! minute, in counters that expire once their window has passed. Events of
+//! tokens from before the token registry existed can't be attributed to a
+//! user and aren't counted. Tokens owned by an org, and their events, count
+//! against the org's quota rather than the member who created them, while
+//! requests always count against the caller's own. Token limits are checked
+//! by the script claiming a token, see `handlers::claim_token`, so
+//! concurrent creations can't overshoot them.
+use crate::error::{ErrorKind, Result};
+use crate::metrics::RedisConnection;
+use crate::Auth;
+use {
+    chrono::Timelike,
+    futures::compat::Future01CompatExt,
+    serde::{Deserialize, Serialize},
+};
+
+/// Quotas by user id or org namespace
+pub const QUOTAS: &str = "mpix.quotas";
+
+/// Lua script that counts one more use of a limit, only if the limit isn't
+/// exhausted yet. Returns the uses counted so far, negated if the limit was
+/// exhausted.
+///
+/// KEYS: quotas, counter
+/// ARGV: user id, name of the limit in the quota json, counter ttl seconds
+static TAKE: &str = r#"
+local count = tonumber(redis.call('GET', KEYS[2]) or '0')
+local quota = redis.call('HGET', KEYS[1], ARGV[1])
+if quota then
+    local limit = cjson.decode(quota)[ARGV[2]]
+    if limit and limit ~= cjson.null and count >= limit then
+        return -count
+    end
+end
+count = redis.call('INCR', KEYS[2])
+redis.call('EXPIRE', KEYS[2], ARGV[3])
+return count
+"#;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_events_per_day: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_minute: Option<u64>,
+}
+impl redis::FromRedisValue for Quota {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Quota> {
+        match *v {
+            redis::Value::Data(ref bytes) => Ok(serde_json::from_slice(bytes)
+                .map_err(|_| (redis::ErrorKind::TypeError, "Invalid quota json bytes"))?),
+            _ => Err((
+                redis::ErrorKind::TypeError,
+                "Response type not quota compatible.",
+            ))?,
+        }
+    }
+}
+
+fn events_key(user_id: &str, now: chrono::DateTime<chrono::Utc>) -> String {
+    format!("mpix.usage.events:{}:{}", user_id, now.format("%Y-%m-%d"))
+}
+
+fn requests_key(user_id: &str, now: chrono::DateTime<chrono::Utc>) -> String {
+    format!("mpix.usage.requests:{}:{}", user_id, now.timestamp() / 60)
+}
+
+/// Seconds until the current request window ends
+pub fn retry_after_secs() -> u32 {
+    60 - chrono::Utc::now().second().min(59)
+}
+
+/// When the current event window ends
+fn next_day(now: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+    (now.date() + chrono::Duration::days(1)).and_hms(0, 0, 0)
+}
+
+async fn take(
+    conn: RedisConnection,
+    user_id: &str,
+    limit: &str,
+    counter: String,
+    ttl_secs: i64,
+) -> Result<(RedisConnection, bool)> {
+    let (conn, count): (_, i64) = redis::cmd("EVAL")
+        .arg(TAKE)
+        .arg(2)
+        .arg(QUOTAS)
+        .arg(counter)
+        .arg(user_id)
+        .arg(limit)
+        .arg(ttl_secs)
+        .query_async(conn)
+        .compat()
+        .await?;
+    Ok((conn, taken(count)))
+}
+
+/// Whether `TAKE` counted a use, given the count it returned. Limits of zero
+/// are exhausted from the start, with a count of `-0`.
+fn taken(count: i64) -> bool {
+    count > 0
+}
+
+/// Count an event recorded for a token owned by `user_id`, returning
+/// whether the owner's daily event quota had room for it
+pub async fn take_event(conn: RedisConnection, user_id: &str) -> Result<(RedisConnection, bool)> {
+    let now = chrono::Utc::now();
+    take(
+        conn,
+        user_id,
+        "max_events_per_day",
+        events_key(user_id, now),
+        60 * 60 * 48,
+    )
+    .await
+}
+
+/// Count a request made by `auth`, rejecting it once the caller's requests
+/// per minute are exhausted
+pub async fn take_request(conn: RedisConnection, auth: &Auth) -> Result<RedisConnection> {
+    let now = chrono::Utc::now();
+    let key = requests_key(&auth.user_token, now);
+    let (conn, taken) = take(conn, &auth.user_token, "max_requests_per_minute", key, 120).await?;
+    if !taken {
+        Err(ErrorKind::TooManyRequests(
+            "request quota exhausted, try again next minute".into(),
+        ))?
+    }
+    Ok(conn)
+}
+
+/// Whether `user_id`'s quota limits their requests per minute, requests of
+/// users it doesn't aren't counted
+pub async fn limits_requests(
+    conn: RedisConnection,
+    user_id: &str,
+) -> Result<(RedisConnection, bool)> {
+    let (conn, quota): (_, Option<Quota>) = redis::cmd("HGET")
+        .arg(QUOTAS)
+        .arg(user_id)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let limited = quota
+        .and_then(|quota| quota.max_requests_per_minute)
+        .is_some();
+    Ok((conn, limited))
+}
+
+pub fn token_quota_exhausted() -> ErrorKind {
+    ErrorKind::Forbidden("token quota reached, no more tokens can be created".into())
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Used {
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Usage {
+    pub tokens: Used,
+    pub events_today: Used,
+    /// When the daily event count starts over
+    #[serde(serialize_with = "crate::timezone::serialize")]
+    pub events_reset: chrono::DateTime<chrono::Utc>,
+    pub requests_this_minute: Used,
+}
+
+/// Reads the usage of tokens and events owned by `namespace` and the
+/// requests made by `user_id`, along with both their quotas
+fn usage_pipe(
+    namespace: &str,
+    user_id: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.cmd("HGET")
+        .arg(QUOTAS)
+        .arg(namespace)
+        .cmd("HLEN")
+        .arg(format!("mpix.user_tokens:{}", namespace))
+        .cmd("GET")
+        .arg(events_key(namespace, now))
+        .cmd("HGET")
+        .arg(QUOTAS)
+        .arg(user_id)
+        .cmd("GET")
+        .arg(requests_key(user_id, now));
+    pipe
+}
+
+/// What `auth` has used of its quotas. Tokens and events count against the
+/// namespace it acts in, requests against its user.
+pub async fn usage(conn: RedisConnection, auth: &Auth) -> Result<(RedisConnection, Usage)> {
+    let now = chrono::Utc::now();
+    let pipe = usage_pipe(&auth.namespace(), &auth.user_token, now);
+    type Stored = (Option<Quota>, u64, Option<u64>, Option<Quota>, Option<u64>);
+    let (conn, (quota, tokens, events, user_quota, requests)): (_, Stored) =
+        pipe.query_async(conn).compat().await?;
+    let quota = quota.unwrap_or_default();
+    let usage = Usage {
+        tokens: Used {
+            used: tokens,
+            limit: quota.max_tokens,
+        },
+        events_today: Used {
+            used: events.unwrap_or(0),
+            limit: quota.max_events_per_day,
+        },
+        events_reset: next_day(now),
+        requests_this_minute: Used {
+            used: requests.unwrap_or(0),
+            limit: user_quota.unwrap_or_default().max_requests_per_minute,
+        },
+    };
+    Ok((conn, usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_windowed() {
+        let at = |s: &str| {
+            chrono::DateTime::parse_from_rfc3339(s)
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        assert_eq!(
+            events_key("u", at("2024-03-01T23:59:59Z")),
+            "mpix.usage.events:u:2024-03-01"
+        );
+        assert_ne!(
+            events_key("u", at("2024-03-01T23:59:59Z")),
+            events_key("u", at("2024-03-02T00:00:00Z"))
+        );
+        assert_eq!(
+            requests_key("u", at("2024-03-01T10:00:00Z")),
+            requests_key("u", at("2024-03-01T10:00:59Z"))
+        );
+        assert_ne!(
+            requests_key("u", at("2024-03-01T10:00:59Z")),
+            requests_key("u", at("2024-03-01T10:01:00Z"))
+        );
+        assert_eq!(
+            next_day(at("2024-02-29T13:00:00Z")),
+            at("2024-03-01T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn org_usage_is_read_from_the_org() {
+        let now = chrono::Utc::now();
+        let packed = String::from_utf8(
+            usage_pipe(&crate::orgs::namespace("acme"), "jane-id", now).get_packed_pipeline(false),
+        )
+        .unwrap();
+        // the quota and tokens an org member's creations are checked against
+        assert!(packed.contains("\r\norg:acme\r\n"));
+        assert!(packed.contains("mpix.user_tokens:org:acme\r\n"));
+        assert!(packed.contains(&events_key("org:acme", now)));
+        // while their requests stay their own
+        assert!(packed.contains("\r\njane-id\r\n"));
+        assert!(packed.contains(&requests_key("jane-id", now)));
+        assert!(!packed.contains("mpix.user_tokens:jane-id"));
+    }
+
+    #[test]
+    fn exhausted_limits_drop_events() {
+        assert!(taken(1));
+        assert!(taken(1000));
+        // a daily quota of 3 events that's been used up
+        assert!(!taken(-3));
+        assert!(!taken(0));
+    }
+
+    #[test]
+    fn missing_limits_are_unlimited() {
+        let quota: Quota = serde_json::from_str(r#"{"max_tokens":5}"#).unwrap();
+        assert_eq!(quota.max_tokens, Some(5));
+        assert_eq!(quota.max_events_per_day, None);
+        assert_eq!(serde_json::to_string(&Quota::default()).unwrap(), "{}");
+    }
+}
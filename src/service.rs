@@ -6,79 +6,319 @@ use {
     },
     futures_util::{compat::Stream01CompatExt, TryStreamExt},
     hyper::{
+        body::Payload,
         header::{HeaderMap, HeaderValue},
-        service::service_fn,
+        server::conn::AddrStream,
+        service::{make_service_fn, service_fn},
         Body, Method, Request, Response, Server, StatusCode,
     },
-    std::{collections::HashSet, io::Write, net::SocketAddr},
+    std::{io::Write, net::SocketAddr},
 };
 
 use crate::configuration::CONFIG;
 use crate::error::{Error, ErrorKind, Result};
-use crate::{handlers, Auth};
-use crate::{router, User};
+use crate::metrics::{self, RedisConnection};
+use crate::{auth_cache, crypto, handlers, Auth, RemoteAddr};
+use crate::{keys, orgs, quotas, router, sessions, Account, Scope};
 
 lazy_static::lazy_static! {
     pub static ref LOG: slog::Logger = { crate::LOG.new(slog::o!("mod" => "service")) };
 }
 
-async fn is_valid_auth(auth_token: String) -> Result<Auth> {
+/// Value of the cookie named `name`, if the request has one
+pub(crate) fn cookie(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|hv| hv.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|pair| {
+            let mut kv = pair.trim().splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v.to_string()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Check an api key, acting in the org it was issued for or else the
+/// `requested` one. Keys checked recently are taken from `auth_cache`, other
+/// keys are checked on a connection that's handed back along with them.
+pub(crate) async fn is_valid_auth(
+    auth_token: String,
+    requested: Option<String>,
+) -> Result<(Option<RedisConnection>, Auth)> {
     slog::debug!(LOG, "checking auth");
-    let conn = redis::Client::open(CONFIG.redis_url.as_ref())?
+    if !CONFIG.auth_token.is_empty() && crypto::constant_time_eq(&auth_token, &CONFIG.auth_token) {
+        return Ok((None, Auth::admin()));
+    }
+    if let Some(auth) = auth_cache::lookup(&auth_token, requested.as_deref()) {
+        return Ok((None, auth));
+    }
+    let generation = auth_cache::generation();
+    let conn = metrics::RedisClient::open(CONFIG.redis_url.as_ref())?
         .get_async_connection()
         .compat()
         .await?;
-    let (_, opt): (_, Option<User>) = redis::cmd("HGET")
-        .arg("mpix.users")
-        .arg(&auth_token)
-        .query_async(conn)
-        .compat()
-        .await
-        .unwrap();
+    let (conn, opt) = keys::lookup(conn, &auth_token).await?;
     slog::debug!(LOG, "authorized user";
-                 "user" => format!("{:?}", opt));
-    if let Some(_) = opt {
-        Ok(Auth {
-            user_token: auth_token,
-        })
+                 "user" => opt.as_ref().map(|(_, user)| user.name.as_str()));
+    if let Some((prefix, user)) = opt {
+        if user.is_expired() {
+            Err(ErrorKind::InvalidAuth(format!(
+                "api key `{}` has expired",
+                user.key_name()
+            )))?
+        }
+        let user_id = user.id.clone().ok_or("api key without a user id")?;
+        // users from before accounts existed have no account to be disabled by
+        let mut pipe = redis::pipe();
+        pipe.cmd("HGET")
+            .arg("mpix.accounts")
+            .arg(&user.name)
+            .cmd("HGET")
+            .arg(quotas::QUOTAS)
+            .arg(&user_id)
+            .cmd("HSET")
+            .arg(keys::KEY_LAST_USED)
+            .arg(&prefix)
+            .arg(chrono::Utc::now().to_rfc3339())
+            .ignore();
+        let (conn, (account, quota)): (_, (Option<Account>, Option<quotas::Quota>)) =
+            pipe.query_async(conn).compat().await?;
+        if account.map(|a| a.disabled).unwrap_or(false) {
+            Err(ErrorKind::InvalidAuth("user is disabled".into()))?
+        }
+        let auth = Auth {
+            scopes: user.scopes(),
+            user_token: user_id,
+            user_name: user.name.clone(),
+            key_id: Some(prefix),
+            csrf_token: None,
+            orgs: vec![],
+            org: None,
+            personal: true,
+            rate_limited: quota
+                .and_then(|quota| quota.max_requests_per_minute)
+                .is_some(),
+        };
+        let (conn, auth) =
+            orgs::activate(conn, auth, requested.as_deref(), user.org.as_deref()).await?;
+        auth_cache::store(
+            &auth_token,
+            requested.as_deref(),
+            &auth,
+            user.expires,
+            generation,
+        );
+        Ok((Some(conn), auth))
     } else {
-        Err(ErrorKind::InvalidAuth("missing auth token".into()))?
+        Err(ErrorKind::InvalidAuth("invalid api key".into()))?
     }
 }
 
-/// Require an auth bearer token on requests
-async fn ensure_auth(
-    req: Request<Body>,
-) -> Result<(Request<Body>, Option<Auth>, Option<Response<Body>>)> {
-    lazy_static::lazy_static! {
-        static ref ALLOWED: HashSet<&'static str> = maplit::hashset!{"", "/status"};
-    };
+/// What `authenticate` made of the credentials a request came with
+pub(crate) enum Credentials {
+    Missing,
+    /// Credentials that were checked and rejected
+    Invalid(Error),
+    /// Credentials that couldn't be checked, e.g. because redis is down
+    Unchecked(Error),
+    Valid(Auth),
+}
+
+/// The api key a request came with, from an `Authorization: Bearer` header
+/// or the `x-mpix-auth` header, in that order
+fn credential(req: &Request<Body>) -> Result<Option<String>> {
+    let malformed = |_| ErrorKind::InvalidAuth("malformed credentials".into());
+    if let Some(hv) = req.headers().get("authorization") {
+        let hv = hv.to_str().map_err(malformed)?.trim();
+        let mut parts = hv.splitn(2, ' ');
+        return match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                Ok(Some(token.trim().to_string()))
+            }
+            _ => Err(ErrorKind::InvalidAuth(
+                "unsupported authorization scheme, expected `Bearer`".into(),
+            ))?,
+        };
+    }
+    match req.headers().get("x-mpix-auth") {
+        Some(hv) => Ok(Some(hv.to_str().map_err(malformed)?.to_string())),
+        None => Ok(None),
+    }
+}
 
-    let path = req.uri().path().trim_end_matches("/");
-    if ALLOWED.contains(path) || path.starts_with("/p/") {
-        return Ok((req, None, None));
+/// Sort a failed credential check into rejected and unchecked credentials
+fn failed_check(err: Error) -> Credentials {
+    match err.kind() {
+        ErrorKind::InvalidAuth(_) | ErrorKind::Forbidden(_) => Credentials::Invalid(err),
+        _ => {
+            slog::error!(LOG, "unable to check credentials"; "error" => format!("{}", err));
+            Credentials::Unchecked(err)
+        }
+    }
+}
+
+/// Check the credentials of a request, an api key or else a session cookie,
+/// along with the connection they were checked on, if any.
+/// Whether a request needs valid ones is up to the route it matches, see
+/// `ensure_auth`, but requests making changes with a session must carry its
+/// csrf token whatever the route.
+async fn authenticate(req: Request<Body>) -> Result<Authenticated> {
+    let org = orgs::requested(&req);
+    match credential(&req) {
+        Ok(Some(auth_token)) => {
+            return Ok(match is_valid_auth(auth_token, org).await {
+                Ok((conn, auth)) => (req, Credentials::Valid(auth), conn),
+                Err(err) => (req, failed_check(err), None),
+            });
+        }
+        Ok(None) => (),
+        Err(err) => return Ok((req, Credentials::Invalid(err), None)),
     }
+    let session_id = match cookie(&req, sessions::SESSION_COOKIE) {
+        Some(session_id) => session_id,
+        None => return Ok((req, Credentials::Missing, None)),
+    };
+    let looked_up = match metrics::RedisClient::open(CONFIG.redis_url.as_ref()) {
+        Ok(client) => match client.get_async_connection().compat().await {
+            Ok(conn) => sessions::lookup(conn, &session_id, org.as_deref()).await,
+            Err(err) => Err(err.into()),
+        },
+        Err(err) => Err(err.into()),
+    };
+    match looked_up {
+        Ok((conn, auth)) => {
+            let req = sessions::check_csrf(req, &auth).await?;
+            Ok((req, Credentials::Valid(auth), Some(conn)))
+        }
+        Err(err) => Ok((req, failed_check(err), None)),
+    }
+}
+
+/// A request, what `authenticate` made of its credentials and the connection
+/// they were checked on
+type Authenticated = (Request<Body>, Credentials, Option<RedisConnection>);
 
-    let maybe_auth = req
-        .headers()
-        .get("x-mpix-auth")
-        .ok_or_else(|| Error::from("missing auth header"))
-        .and_then(|hv| Ok(hv.to_str()?.to_string()))
-        .ok();
-    if let Some(auth_token) = maybe_auth {
-        if let Some(auth) = is_valid_auth(auth_token).await.ok() {
-            return Ok((req, Some(auth), None));
+/// Count a request against its caller's requests per minute quota, if their
+/// quota limits them, reusing the connection its credentials were checked on
+async fn limit_requests(conn: Option<RedisConnection>, auth: &Auth) -> Result<()> {
+    if !auth.rate_limited {
+        return Ok(());
+    }
+    let conn = match conn {
+        Some(conn) => conn,
+        None => {
+            metrics::RedisClient::open(CONFIG.redis_url.as_ref())?
+                .get_async_connection()
+                .compat()
+                .await?
         }
+    };
+    quotas::take_request(conn, auth).await?;
+    Ok(())
+}
+
+/// Require the credentials of a request to be valid and have the `required`
+/// scope of the route it matched. Public routes, with no required scope, are
+/// let through without valid credentials but still pick up the user when
+/// they're present.
+pub(crate) fn ensure_auth(
+    credentials: Credentials,
+    required: Option<Scope>,
+) -> Result<Option<Auth>> {
+    let required = match required {
+        Some(required) => required,
+        None => match credentials {
+            Credentials::Valid(auth) => return Ok(Some(auth)),
+            _ => return Ok(None),
+        },
+    };
+    match credentials {
+        Credentials::Missing => Err(ErrorKind::MissingAuth(
+            "missing credentials, expected `Authorization: Bearer <api key>`".into(),
+        ))?,
+        Credentials::Invalid(err) | Credentials::Unchecked(err) => Err(err),
+        Credentials::Valid(auth) if !auth.has_scope(required) => Err(ErrorKind::Forbidden(
+            format!("api key is missing the `{}` scope", required.as_str()),
+        ))?,
+        Credentials::Valid(auth) => Ok(Some(auth)),
     }
+}
+
+/// Everything a response's content was computed from. Handlers attach one to
+/// responses that clients can revalidate, and `cache_response` turns it into
+/// an etag.
+pub(crate) struct ETagSource(pub String);
+
+/// Whether an `If-None-Match` header value matches `etag`, using the weak
+/// comparison conditional GETs call for
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Set caching headers, answering conditional GETs of unchanged content
+/// with a `304 Not Modified`. Responses are private to the caller, those
+/// with an etag can be cached but must be revalidated and everything else
+/// is not stored unless its handler says otherwise.
+fn cache_response(
+    method: &Method,
+    headers: &HeaderMap,
+    mut resp: Response<Body>,
+) -> Result<Response<Body>> {
+    let source = match resp.extensions().get::<ETagSource>() {
+        Some(ETagSource(source)) if resp.status() == StatusCode::OK => Some(source),
+        _ => None,
+    };
+    let etag = match source {
+        Some(source) => format!("W/\"{}\"", crypto::digest(source)),
+        None => {
+            if !resp.headers().contains_key("cache-control") {
+                resp.headers_mut()
+                    .insert("cache-control", HeaderValue::from_static("no-store"));
+            }
+            return Ok(resp);
+        }
+    };
 
-    let resp = Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .body(Body::from("unauthorized"))?;
-    Ok((req, None, Some(resp)))
+    let not_modified = (method == Method::GET || method == Method::HEAD)
+        && headers
+            .get("if-none-match")
+            .and_then(|hv| hv.to_str().ok())
+            .map(|if_none_match| etag_matches(if_none_match, &etag))
+            .unwrap_or(false);
+    let mut resp = if not_modified {
+        Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())?
+    } else {
+        resp
+    };
+    let resp_headers = resp.headers_mut();
+    resp_headers.insert("etag", HeaderValue::from_str(&etag)?);
+    resp_headers.insert(
+        "cache-control",
+        HeaderValue::from_static("private, no-cache"),
+    );
+    resp_headers.insert(
+        "vary",
+        HeaderValue::from_static("authorization, x-mpix-auth, x-mpix-org, cookie"),
+    );
+    Ok(resp)
 }
 
 /// gzip response content if the request accepts gzip
 async fn gzip_response(headers: HeaderMap, mut resp: Response<Body>) -> Result<Response<Body>> {
+    // streamed bodies have no known length and would need
+    // to be buffered in memory to be compressed here
+    if resp.body().content_length().is_none() || resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(resp);
+    }
     if let Some(accept) = headers.get("accept-encoding") {
         if accept.to_str()?.contains("gzip") {
             resp.headers_mut()
@@ -97,6 +337,7 @@ async fn gzip_response(headers: HeaderMap, mut resp: Response<Body>) -> Result<R
                 .finish()
                 .map_err(|e| format!("error finishing gzip {:?}", e))?;
             let res_size = res.len();
+            metrics::GZIP_BYTES_SAVED.inc_by(bytes_size.saturating_sub(res_size) as u64);
             let new_bod = Body::from(res);
 
             let resp = Response::from_parts(parts, new_bod);
@@ -110,20 +351,115 @@ async fn gzip_response(headers: HeaderMap, mut resp: Response<Body>) -> Result<R
     Ok(resp)
 }
 
+/// Value of the `Allow` header of a path whose routes have `methods`. Paths
+/// that can be fetched can also be fetched with `HEAD`, and every path
+/// answers `OPTIONS`.
+fn allow_header(methods: &[Method]) -> String {
+    let mut allowed: Vec<&str> = vec![];
+    for method in methods {
+        if !allowed.contains(&method.as_str()) {
+            allowed.push(method.as_str());
+        }
+        if *method == Method::GET && !allowed.contains(&"HEAD") {
+            allowed.push("HEAD");
+        }
+    }
+    allowed.push("OPTIONS");
+    allowed.join(", ")
+}
+
+/// Answer a request for a path that exists, but has no route for the
+/// request's method, with the methods it does have: `OPTIONS` requests are
+/// answered outright and everything else is a `405 Method Not Allowed`
+pub(crate) fn allowed_methods(method: &Method, allowed: &[Method]) -> Result<Response<Body>> {
+    let allow = HeaderValue::from_str(&allow_header(allowed))?;
+    let mut resp = if *method == Method::OPTIONS {
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?
+    } else {
+        error_response(
+            ErrorKind::MethodNotAllowed(format!("method {} not allowed", method)).into(),
+        )?
+    };
+    resp.headers_mut().insert("allow", allow);
+    Ok(resp)
+}
+
+/// The response to a `HEAD` request, which is the response to the `GET`
+/// request it was served as, without the body
+fn head_response(resp: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = resp.into_parts();
+    if let Some(len) = body.content_length() {
+        parts
+            .headers
+            .entry("content-length")
+            .expect("content-length is a valid header name")
+            .or_insert_with(|| HeaderValue::from(len));
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+/// Scopes routes require of api keys, public routes don't require credentials
+const PUBLIC: Option<Scope> = None;
+const READ: Option<Scope> = Some(Scope::StatsRead);
+const WRITE: Option<Scope> = Some(Scope::TokensCreate);
+const ADMIN: Option<Scope> = Some(Scope::Admin);
+
 async fn route(
     req: Request<Body>,
-    auth: Option<Auth>,
+    credentials: Credentials,
     method: Method,
     uri: String,
 ) -> Result<Response<Body>> {
     router!(
-         req, auth, method, uri.trim_end_matches("/"),
-         [Method::GET, r"^/p/(?P<token>[a-zA-Z0-9-_]+)$", {"token"}] -> handlers::track,
-         [Method::GET, r"^/status$", {}] -> handlers::status,
-         [Method::GET, r"^$", {}] -> handlers::index,
-         [Method::POST, r"^/create$", {}] -> handlers::create,
-         [Method::GET, r"^/stat$", {}] -> handlers::tracking_stats,
-         [Method::GET, r"^/stat/(?P<token>[a-zA-Z0-9-_]+)$", {"token"}] -> handlers::tracking_stats,
+         req, credentials, method, uri.trim_end_matches("/"),
+         [Method::GET, r"^/p/(?P<token>[a-zA-Z0-9-_]+)$", {"token"}, PUBLIC] -> handlers::track,
+         [Method::GET, r"^/status$", {}, PUBLIC] -> handlers::status,
+         [Method::GET, r"^/metrics$", {}, PUBLIC] -> handlers::metrics,
+         [Method::GET, r"^$", {}, PUBLIC] -> handlers::dashboard::index,
+         [Method::POST, r"^/login$", {}, PUBLIC] -> handlers::dashboard::login,
+         [Method::POST, r"^/logout$", {}, PUBLIC] -> handlers::dashboard::logout,
+         [Method::POST, r"^/dashboard/create$", {}, PUBLIC] -> handlers::dashboard::create,
+         [Method::GET, r"^/dashboard/token/(?P<token>[a-zA-Z0-9-_]+)$", {"token"}, PUBLIC] -> handlers::dashboard::token,
+         [Method::GET, r"^/me/usage$", {}, READ] -> handlers::usage,
+         [Method::GET, r"^/orgs$", {}, READ] -> handlers::orgs::list_orgs,
+         [Method::GET, r"^/orgs/(?P<org>[a-zA-Z0-9-_.@]+)/members$", {"org"}, READ] -> handlers::orgs::list_members,
+         [Method::POST, r"^/orgs/(?P<org>[a-zA-Z0-9-_.@]+)/members$", {"org"}, WRITE] -> handlers::orgs::set_member,
+         [Method::DELETE, r"^/orgs/(?P<org>[a-zA-Z0-9-_.@]+)/members/(?P<user>[a-zA-Z0-9-_.@]+)$", {"org", "user"}, WRITE] -> handlers::orgs::remove_member,
+         [Method::POST, r"^/create$", {}, WRITE] -> handlers::create,
+         [Method::POST, r"^/create/bulk$", {}, WRITE] -> handlers::create_bulk,
+         [Method::GET, r"^/stat$", {}, READ] -> handlers::tracking_stats,
+         [Method::GET, r"^/stat/export$", {}, READ] -> handlers::export_all,
+         [Method::POST, r"^/stat/batch$", {}, READ] -> handlers::batch_stats,
+         [Method::GET, r"^/stat/(?P<token>[a-zA-Z0-9-_]+)$", {"token"}, READ] -> handlers::tracking_stats,
+         [Method::GET, r"^/stat/(?P<token>[a-zA-Z0-9-_]+)/summary$", {"token"}, READ] -> handlers::token_summary,
+         [Method::GET, r"^/stat/(?P<token>[a-zA-Z0-9-_]+)/export$", {"token"}, READ] -> handlers::export_token,
+         [Method::POST, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/archive$", {"token"}, WRITE] -> handlers::archive,
+         [Method::POST, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/unarchive$", {"token"}, WRITE] -> handlers::unarchive,
+         [Method::GET, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/access$", {"token"}, READ] -> handlers::list_access,
+         [Method::POST, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/access$", {"token"}, WRITE] -> handlers::grant_access,
+         [Method::DELETE, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/access/(?P<user>[a-zA-Z0-9-_.@]+)$", {"token", "user"}, WRITE] -> handlers::revoke_access,
+         [Method::GET, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/share$", {"token"}, READ] -> handlers::list_share_links,
+         [Method::POST, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/share$", {"token"}, WRITE] -> handlers::create_share_link,
+         [Method::DELETE, r"^/token/(?P<token>[a-zA-Z0-9-_]+)/share/(?P<share_id>[a-zA-Z0-9-_.]+)$", {"token", "share_id"}, WRITE] -> handlers::revoke_share_link,
+         [Method::GET, r"^/share/(?P<share_id>[a-zA-Z0-9-_.]+)$", {"share_id"}, PUBLIC] -> handlers::shared_stats,
+         [Method::GET, r"^/admin/audit$", {}, ADMIN] -> handlers::admin::list_audit,
+         [Method::GET, r"^/admin/orgs$", {}, ADMIN] -> handlers::admin::list_orgs,
+         [Method::POST, r"^/admin/orgs$", {}, ADMIN] -> handlers::admin::create_org,
+         [Method::GET, r"^/admin/orgs/(?P<org>[a-zA-Z0-9-_.@]+)/quota$", {"org"}, ADMIN] -> handlers::admin::get_org_quota,
+         [Method::POST, r"^/admin/orgs/(?P<org>[a-zA-Z0-9-_.@]+)/quota$", {"org"}, ADMIN] -> handlers::admin::set_org_quota,
+         [Method::GET, r"^/admin/users$", {}, ADMIN] -> handlers::admin::list_users,
+         [Method::POST, r"^/admin/users$", {}, ADMIN] -> handlers::admin::create_user,
+         [Method::DELETE, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)$", {"name"}, ADMIN] -> handlers::admin::delete_user,
+         [Method::POST, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/disable$", {"name"}, ADMIN] -> handlers::admin::disable_user,
+         [Method::POST, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/enable$", {"name"}, ADMIN] -> handlers::admin::enable_user,
+         [Method::POST, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/password$", {"name"}, ADMIN] -> handlers::admin::set_password,
+         [Method::GET, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/quota$", {"name"}, ADMIN] -> handlers::admin::get_quota,
+         [Method::POST, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/quota$", {"name"}, ADMIN] -> handlers::admin::set_quota,
+         [Method::GET, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/keys$", {"name"}, ADMIN] -> handlers::admin::list_keys,
+         [Method::POST, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/keys$", {"name"}, ADMIN] -> handlers::admin::issue_key,
+         [Method::DELETE, r"^/admin/users/(?P<name>[a-zA-Z0-9-_.@]+)/keys/(?P<key_name>[a-zA-Z0-9-_.]+)$", {"name", "key_name"}, ADMIN] -> handlers::admin::revoke_key,
          _ -> handlers::not_found,
     );
 }
@@ -133,21 +469,65 @@ async fn process(req: Request<Body>) -> Result<Response<Body>> {
     let headers = req.headers().clone();
 
     // before
-    let (req, auth, resp) = ensure_auth(req).await?;
-    if let Some(resp) = resp {
-        return Ok(resp);
+    let (req, credentials, conn) = authenticate(req).await?;
+    if let Credentials::Valid(auth) = &credentials {
+        limit_requests(conn, auth).await?;
     }
 
     // route
     let method = req.method().clone();
     let uri = req.uri().path().to_string();
-    let resp = route(req, auth, method, uri).await?;
+    let resp = route(req, credentials, method.clone(), uri).await?;
 
     // after
+    let resp = cache_response(&method, &headers, resp)?;
     let resp = gzip_response(headers, resp).await?;
+    if method == Method::HEAD {
+        return Ok(head_response(resp));
+    }
     Ok(resp)
 }
 
+/// Render an error as a response, hiding the details of server errors.
+/// Authentication failures say how to authenticate with `WWW-Authenticate`.
+pub(crate) fn error_response(err: Error) -> Result<Response<Body>> {
+    let status = err.status();
+    let mut builder = Response::builder();
+    builder.status(status);
+    match err.kind() {
+        ErrorKind::MissingAuth(_) => {
+            builder.header("www-authenticate", r#"Bearer realm="mpix""#);
+        }
+        ErrorKind::InvalidAuth(_) => {
+            builder.header(
+                "www-authenticate",
+                r#"Bearer realm="mpix", error="invalid_token""#,
+            );
+        }
+        ErrorKind::TooManyRequests(_) => {
+            builder.header(
+                "retry-after",
+                quotas::retry_after_secs().to_string().as_str(),
+            );
+        }
+        _ => (),
+    }
+    if status.is_server_error() {
+        slog::error!(LOG, "handler error";
+                     "error" => format!("{}", err));
+        let body = if status == StatusCode::SERVICE_UNAVAILABLE {
+            "service unavailable"
+        } else {
+            "server error"
+        };
+        Ok(builder.body(body.into())?)
+    } else {
+        slog::debug!(LOG, "client error";
+                     "error" => format!("{}", err));
+        Ok(builder.body(format!("{}", err).into())?)
+    }
+}
+
 async fn serve(req: Request<Body>) -> Result<Response<Body>> {
     // capture incoming info for logs
     let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
@@ -157,17 +537,12 @@ async fn serve(req: Request<Body>) -> Result<Response<Body>> {
 
     let response = match process(req).await {
         Ok(resp) => resp,
-        Err(err) => {
-            slog::error!(LOG, "handler error";
-                         "error" => format!("{}", err));
-            Response::builder()
-                .status(500)
-                .body("server error".into())?
-        }
+        Err(err) => error_response(err)?,
     };
 
     let status = response.status();
     let elap = start.elapsed();
+    metrics::observe_request(&response, elap);
     let elap_ms = (elap.as_secs_f32() * 1_000.) + (elap.subsec_nanos() as f32 / 1_000_000.);
     slog::info!(LOG, "request";
                 "method" => method.as_str(),
@@ -178,18 +553,47 @@ async fn serve(req: Request<Body>) -> Result<Response<Body>> {
     Ok(response)
 }
 
+/// Migrate plaintext api keys and adopt the users they belong to, returns
+/// how many keys were migrated and users adopted
+async fn migrate_legacy() -> Result<(usize, usize)> {
+    let conn = metrics::RedisClient::open(CONFIG.redis_url.as_ref())?
+        .get_async_connection()
+        .compat()
+        .await?;
+    let (conn, migrated) = keys::migrate_plaintext(conn).await?;
+    let (_, adopted) = handlers::admin::adopt_legacy_users(conn).await?;
+    Ok((migrated, adopted))
+}
+
 /// Build a server future that can be passed to a runtime
 pub async fn run(addr: SocketAddr) {
+    // keys that aren't migrated here are migrated when they're next used
+    match migrate_legacy().await {
+        Ok((migrated, adopted)) => {
+            if migrated > 0 {
+                slog::info!(LOG, "migrated plaintext api keys"; "count" => migrated);
+            }
+            if adopted > 0 {
+                slog::info!(LOG, "adopted legacy users"; "count" => adopted);
+            }
+        }
+        Err(e) => {
+            slog::error!(LOG, "error migrating legacy users"; "error" => format!("{}", e))
+        }
+    }
+    auth_cache::subscribe();
     slog::info!(LOG, "Listening"; "host" => format!("http://{}", addr));
 
-    let server_future = Server::bind(&addr).serve(|| {
-        service_fn(|req| {
+    let server_future = Server::bind(&addr).serve(make_service_fn(|conn: &AddrStream| {
+        let remote_addr = RemoteAddr(conn.remote_addr());
+        service_fn(move |mut req: Request<Body>| {
+            req.extensions_mut().insert(remote_addr);
             // `serve` returns a `std::future` so we need to box and
             // wrap it to make it futures01 compatible before handing
             // it over to hyper
             serve(req).boxed().compat()
         })
-    });
+    }));
 
     // and now `server_future` is a futures01 future that we need to
     // make `std::futures` compatible so we can `.await` it
@@ -197,3 +601,184 @@ pub async fn run(addr: SocketAddr) {
         slog::error!(LOG, "server error"; "error" => format!("{}", e));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(source: &str) -> Response<Body> {
+        let mut resp = Response::new(Body::from("{}"));
+        resp.extensions_mut().insert(ETagSource(source.into()));
+        resp
+    }
+
+    #[test]
+    fn unchanged_content_is_not_modified() {
+        let resp = cache_response(&Method::GET, &HeaderMap::new(), tagged("tok\n3\n1700")).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["cache-control"], "private, no-cache");
+        let etag = resp.headers()["etag"].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("if-none-match", etag.clone());
+        let resp = cache_response(&Method::GET, &headers, tagged("tok\n3\n1700")).unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()["etag"], etag);
+
+        let resp = cache_response(&Method::GET, &headers, tagged("tok\n4\n1800")).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()["etag"], etag);
+    }
+
+    #[test]
+    fn paths_answer_with_the_methods_they_allow() {
+        assert_eq!(allow_header(&[Method::GET]), "GET, HEAD, OPTIONS");
+        assert_eq!(
+            allow_header(&[Method::GET, Method::POST, Method::GET]),
+            "GET, HEAD, POST, OPTIONS"
+        );
+
+        let resp = allowed_methods(&Method::OPTIONS, &[Method::POST]).unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()["allow"], "POST, OPTIONS");
+
+        let resp = allowed_methods(&Method::DELETE, &[Method::GET]).unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()["allow"], "GET, HEAD, OPTIONS");
+    }
+
+    #[test]
+    fn head_responses_keep_headers_without_the_body() {
+        let resp = Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from("{\"ok\":true}"))
+            .unwrap();
+        let resp = head_response(resp);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(resp.headers()["content-length"], "11");
+        let body = futures::executor::block_on(resp.into_body().compat().try_concat()).unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn routes_require_their_scope() {
+        let reader = || {
+            Credentials::Valid(Auth {
+                user_token: "reader-id".into(),
+                user_name: "reader".into(),
+                scopes: vec![Scope::StatsRead],
+                key_id: None,
+                csrf_token: None,
+                orgs: vec![],
+                org: None,
+                personal: true,
+                rate_limited: false,
+            })
+        };
+        assert!(ensure_auth(Credentials::Missing, PUBLIC).unwrap().is_none());
+        assert!(ensure_auth(reader(), PUBLIC).unwrap().is_some());
+        assert!(ensure_auth(reader(), READ).unwrap().is_some());
+        assert_eq!(
+            ensure_auth(reader(), WRITE).err().unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+        assert!(ensure_auth(Credentials::Valid(Auth::admin()), ADMIN).is_ok());
+    }
+
+    #[test]
+    fn missing_invalid_and_unchecked_credentials_are_told_apart() {
+        let invalid =
+            || Credentials::Invalid(ErrorKind::InvalidAuth("invalid api key".into()).into());
+        let unchecked = || {
+            let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+            Credentials::Unchecked(redis::RedisError::from(io).into())
+        };
+        // public routes don't care
+        assert!(ensure_auth(invalid(), PUBLIC).unwrap().is_none());
+        assert!(ensure_auth(unchecked(), PUBLIC).unwrap().is_none());
+
+        let resp =
+            |credentials| error_response(ensure_auth(credentials, READ).err().unwrap()).unwrap();
+        let missing = resp(Credentials::Missing);
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            missing.headers()["www-authenticate"],
+            r#"Bearer realm="mpix""#
+        );
+        let invalid = resp(invalid());
+        assert_eq!(invalid.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            invalid.headers()["www-authenticate"],
+            r#"Bearer realm="mpix", error="invalid_token""#
+        );
+        let unchecked = resp(unchecked());
+        assert_eq!(unchecked.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(unchecked.headers().get("www-authenticate").is_none());
+    }
+
+    #[test]
+    fn credentials_are_read_from_bearer_header_first() {
+        let req = |headers: &[(&str, &str)]| {
+            let mut req = Request::builder();
+            for (k, v) in headers {
+                req.header(*k, *v);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let cred = |headers: &[(&str, &str)]| credential(&req(headers));
+        assert_eq!(cred(&[]).unwrap(), None);
+        // sessions are checked separately, and only without an api key
+        assert_eq!(
+            cred(&[("cookie", "mpix_session=session-id")]).unwrap(),
+            None
+        );
+        assert_eq!(
+            cred(&[("x-mpix-auth", "from-header")]).unwrap().unwrap(),
+            "from-header"
+        );
+        assert_eq!(
+            cred(&[
+                ("authorization", "Bearer from-bearer"),
+                ("x-mpix-auth", "from-header")
+            ])
+            .unwrap()
+            .unwrap(),
+            "from-bearer"
+        );
+        assert_eq!(
+            cred(&[("authorization", "Basic dXNlcjpwYXNz")])
+                .err()
+                .unwrap()
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+        // header values that aren't visible ascii are rejected, not failures
+        for name in &["authorization", "x-mpix-auth"] {
+            let mut req = Request::builder();
+            req.header(
+                *name,
+                HeaderValue::from_bytes(b"Bearer caf\xc3\xa9").unwrap(),
+            );
+            let err = credential(&req.body(Body::empty()).unwrap()).err().unwrap();
+            assert_eq!(err.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[test]
+    fn untagged_responses_are_not_stored() {
+        let resp = cache_response(
+            &Method::GET,
+            &HeaderMap::new(),
+            Response::new(Body::empty()),
+        );
+        assert_eq!(resp.unwrap().headers()["cache-control"], "no-store");
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        assert!(etag_matches(r#""abc""#, r#"W/"abc""#));
+        assert!(etag_matches(r#"W/"xyz", W/"abc""#, r#"W/"abc""#));
+        assert!(etag_matches("*", r#"W/"abc""#));
+        assert!(!etag_matches(r#"W/"abd""#, r#"W/"abc""#));
+    }
+}
@@ -0,0 +1,463 @@
+//! Server side sessions for browsers, which can't attach an api key to page
+//! loads. Signing in with a password creates a session in redis, and its id
+//! is handed to the browser in an `HttpOnly`, `SameSite=Strict` cookie.
+//!
+//! Sessions are stored under a digest of their id, so a copy of redis can't
+//! be used to sign in, and each user's sessions are listed in
+//! `mpix.user_sessions:{name}` so they can all be ended at once. Requests
+//! that change anything with a session must also carry its csrf token,
+//! either in the `x-csrf-token` header or as the `csrf_token` field of a form.
+//!
+//! Passwords are checked on threads of their own, see `crypto`, and sign in
+//! attempts are limited per name and per address, see `take_login_attempt`.
+use crate::audit::AUDIT_LOG;
+use crate::configuration::CONFIG;
+use crate::error::{ErrorKind, Result};
+use crate::metrics::RedisConnection;
+use crate::{crypto, orgs, quotas, timezone, Account, Auth, Environment, Scope};
+use {
+    futures::compat::Future01CompatExt,
+    futures_util::{compat::Stream01CompatExt, TryStreamExt},
+    hyper::{Body, Method, Request},
+    serde::{Deserialize, Serialize},
+    std::fmt::Write,
+};
+
+/// Cookie carrying the session id of users signed in with a password
+pub const SESSION_COOKIE: &str = "mpix_session";
+
+/// Header api clients using a session send its csrf token in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Password hashes of users that can sign in, by name
+pub const PASSWORDS: &str = "mpix.passwords";
+
+/// How long a session lasts after signing in
+pub const SESSION_TTL_SECS: i64 = 60 * 60 * 24 * 7;
+
+/// Shortest password that can be set
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Sign in attempts allowed for a name in each window, whoever makes them
+const LOGIN_ATTEMPTS_PER_NAME: u64 = 10;
+
+/// Sign in attempts allowed from an address in each window, whatever name
+const LOGIN_ATTEMPTS_PER_ADDRESS: u64 = 50;
+
+/// How long sign in attempts are counted for before they start over
+const LOGIN_WINDOW_SECS: i64 = 15 * 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    /// Name of the signed in user
+    pub user: String,
+    pub csrf_token: String,
+    #[serde(serialize_with = "timezone::serialize")]
+    pub created: chrono::DateTime<chrono::Utc>,
+}
+impl redis::FromRedisValue for Session {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Session> {
+        match *v {
+            redis::Value::Data(ref bytes) => Ok(serde_json::from_slice(bytes)
+                .map_err(|_| (redis::ErrorKind::TypeError, "Invalid session json bytes"))?),
+            _ => Err((
+                redis::ErrorKind::TypeError,
+                "Response type not session compatible.",
+            ))?,
+        }
+    }
+}
+
+/// Lua script that ends every session of a user, returns how many there were.
+///
+/// KEYS: user sessions set
+static END_SESSIONS: &str = r#"
+local sessions = redis.call('SMEMBERS', KEYS[1])
+for _, session in ipairs(sessions) do
+    redis.call('DEL', session)
+end
+redis.call('DEL', KEYS[1])
+return #sessions
+"#;
+
+/// Lua script that counts one more attempt on each counter, only if none of
+/// them has reached its limit yet. Returns whether the attempt was counted.
+///
+/// KEYS: counters
+/// ARGV: counter ttl seconds, then the limit of each counter
+static TAKE_ATTEMPT: &str = r#"
+for i, key in ipairs(KEYS) do
+    if tonumber(redis.call('GET', key) or '0') >= tonumber(ARGV[i + 1]) then
+        return 0
+    end
+end
+for _, key in ipairs(KEYS) do
+    redis.call('INCR', key)
+    redis.call('EXPIRE', key, ARGV[1])
+end
+return 1
+"#;
+
+fn session_key(id: &str) -> String {
+    format!("mpix.session:{}", crypto::digest(id))
+}
+
+fn user_sessions_key(user: &str) -> String {
+    format!("mpix.user_sessions:{}", user)
+}
+
+fn new_secret() -> String {
+    let mut secret = String::new();
+    for _ in 0..2 {
+        write!(
+            secret,
+            "{}",
+            uuid::Uuid::new_v4()
+                .to_simple()
+                .encode_lower(&mut uuid::Uuid::encode_buffer())
+        )
+        .expect("writing to a string can't fail");
+    }
+    secret
+}
+
+/// Counters of the sign in attempts for `name` and from `address`, if known,
+/// along with their limits
+fn login_counters(
+    name: &str,
+    address: Option<&str>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<(String, u64)> {
+    let window = now.timestamp() / LOGIN_WINDOW_SECS;
+    let name = (
+        format!("mpix.usage.logins:name:{}:{}", name, window),
+        LOGIN_ATTEMPTS_PER_NAME,
+    );
+    let address = address.map(|address| {
+        (
+            format!("mpix.usage.logins:address:{}:{}", address, window),
+            LOGIN_ATTEMPTS_PER_ADDRESS,
+        )
+    });
+    Some(name).into_iter().chain(address).collect()
+}
+
+/// Count an attempt at signing in as `name` from `address`, returning
+/// whether there are attempts left for both
+pub async fn take_login_attempt(
+    conn: RedisConnection,
+    name: &str,
+    address: Option<&str>,
+) -> Result<(RedisConnection, bool)> {
+    let counters = login_counters(name, address, chrono::Utc::now());
+    let mut cmd = redis::cmd("EVAL");
+    cmd.arg(TAKE_ATTEMPT).arg(counters.len());
+    for (key, _) in &counters {
+        cmd.arg(key);
+    }
+    cmd.arg(LOGIN_WINDOW_SECS * 2);
+    for (_, limit) in &counters {
+        cmd.arg(*limit);
+    }
+    let (conn, taken): (_, bool) = cmd.query_async(conn).compat().await?;
+    Ok((conn, taken))
+}
+
+pub fn validate_password(password: &str) -> Result<()> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        Err(ErrorKind::BadRequest(format!(
+            "passwords must be at least {} characters",
+            MIN_PASSWORD_LEN
+        )))?
+    }
+    Ok(())
+}
+
+/// Value of the `set-cookie` header delivering session `id`, an empty id
+/// clears the cookie
+pub fn session_cookie(id: &str) -> String {
+    let max_age = if id.is_empty() { 0 } else { SESSION_TTL_SECS };
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE, id, max_age
+    );
+    if CONFIG.env == Environment::Production {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+/// The account signing in as `name` with `password` is for, if the
+/// password is right and the account isn't disabled
+pub async fn check_password(
+    conn: RedisConnection,
+    name: &str,
+    password: &str,
+) -> Result<(RedisConnection, Option<Account>)> {
+    let mut pipe = redis::pipe();
+    pipe.cmd("HGET")
+        .arg(PASSWORDS)
+        .arg(name)
+        .cmd("HGET")
+        .arg("mpix.accounts")
+        .arg(name);
+    let (conn, (hash, account)): (_, (Option<String>, Option<Account>)) =
+        pipe.query_async(conn).compat().await?;
+    // users without a password take as long to turn away as wrong passwords
+    let verified = crypto::verify_password(hash, password.to_string()).await?;
+    let account = match account {
+        Some(account) if verified => account,
+        _ => return Ok((conn, None)),
+    };
+    if account.disabled {
+        return Ok((conn, None));
+    }
+    Ok((conn, Some(account)))
+}
+
+/// Start a session for `account`, recording the audit `entry`, returning its id
+pub async fn create(
+    conn: RedisConnection,
+    account: &Account,
+    entry: &str,
+) -> Result<(RedisConnection, String)> {
+    let id = new_secret();
+    let session = Session {
+        user: account.name.clone(),
+        csrf_token: new_secret(),
+        created: chrono::Utc::now(),
+    };
+    let key = session_key(&id);
+    let sessions_key = user_sessions_key(&account.name);
+    // the list of sessions lasts as long as the newest of them
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("SET")
+        .arg(&key)
+        .arg(serde_json::to_string(&session)?)
+        .arg("EX")
+        .arg(SESSION_TTL_SECS)
+        .ignore()
+        .cmd("SADD")
+        .arg(&sessions_key)
+        .arg(&key)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(&sessions_key)
+        .arg(SESSION_TTL_SECS)
+        .ignore()
+        .cmd("RPUSH")
+        .arg(AUDIT_LOG)
+        .arg(entry)
+        .ignore();
+    let (conn, ()) = pipe.query_async(conn).compat().await?;
+    Ok((conn, id))
+}
+
+/// End session `id`, recording the audit `entry` if there is one
+pub async fn delete(
+    conn: RedisConnection,
+    id: &str,
+    entry: Option<&str>,
+) -> Result<RedisConnection> {
+    let key = session_key(id);
+    let (conn, session): (_, Option<Session>) = redis::cmd("GET")
+        .arg(&key)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(conn),
+    };
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("DEL")
+        .arg(&key)
+        .ignore()
+        .cmd("SREM")
+        .arg(user_sessions_key(&session.user))
+        .arg(&key)
+        .ignore();
+    if let Some(entry) = entry {
+        pipe.cmd("RPUSH").arg(AUDIT_LOG).arg(entry).ignore();
+    }
+    let (conn, ()) = pipe.query_async(conn).compat().await?;
+    Ok(conn)
+}
+
+/// Build the command ending every session of `user`, returns how many were ended
+pub fn end_all(user: &str) -> redis::Cmd {
+    let mut cmd = redis::cmd("EVAL");
+    cmd.arg(END_SESSIONS).arg(1).arg(user_sessions_key(user));
+    cmd
+}
+
+/// Credentials of the user signed in with session `id`, acting in the
+/// `requested` org. Sessions act with the default scopes of their user, and
+/// end when the user is disabled or deleted.
+pub async fn lookup(
+    conn: RedisConnection,
+    id: &str,
+    requested: Option<&str>,
+) -> Result<(RedisConnection, Auth)> {
+    let (conn, session): (_, Option<Session>) = redis::cmd("GET")
+        .arg(session_key(id))
+        .query_async(conn)
+        .compat()
+        .await?;
+    let session = match session {
+        Some(session) => session,
+        None => Err(ErrorKind::InvalidAuth("session has expired".into()))?,
+    };
+    let (conn, account): (_, Option<Account>) = redis::cmd("HGET")
+        .arg("mpix.accounts")
+        .arg(&session.user)
+        .query_async(conn)
+        .compat()
+        .await?;
+    let auth = match account {
+        Some(account) if !account.disabled => Auth {
+            user_token: account.id,
+            user_name: account.name,
+            scopes: Scope::DEFAULT.to_vec(),
+            key_id: None,
+            csrf_token: Some(session.csrf_token),
+            orgs: vec![],
+            org: None,
+            personal: true,
+            rate_limited: false,
+        },
+        Some(_) => Err(ErrorKind::InvalidAuth("user is disabled".into()))?,
+        None => Err(ErrorKind::InvalidAuth("user no longer exists".into()))?,
+    };
+    let (conn, rate_limited) = quotas::limits_requests(conn, &auth.user_token).await?;
+    let auth = Auth {
+        rate_limited,
+        ..auth
+    };
+    orgs::activate(conn, auth, requested, None).await
+}
+
+fn is_safe(method: &Method) -> bool {
+    *method == Method::GET || *method == Method::HEAD || *method == Method::OPTIONS
+}
+
+/// Require requests that change anything with a session to carry the
+/// session's csrf token. Forms can't set headers, so urlencoded bodies are
+/// read for a `csrf_token` field and handed back to the request.
+pub async fn check_csrf(req: Request<Body>, auth: &Auth) -> Result<Request<Body>> {
+    let expected = match auth.csrf_token.as_ref() {
+        Some(expected) if !is_safe(req.method()) => expected,
+        _ => return Ok(req),
+    };
+    if let Some(hv) = req.headers().get(CSRF_HEADER) {
+        let invalid = || ErrorKind::Forbidden("invalid csrf token".into());
+        if crypto::constant_time_eq(hv.to_str().map_err(|_| invalid())?, expected) {
+            return Ok(req);
+        }
+        Err(invalid())?
+    }
+    let is_form = req
+        .headers()
+        .get("content-type")
+        .and_then(|hv| hv.to_str().ok())
+        .map(|ct| ct.starts_with("application/x-www-form-urlencoded"))
+        .unwrap_or(false);
+    if !is_form {
+        Err(ErrorKind::Forbidden("missing csrf token".into()))?
+    }
+    #[derive(Deserialize)]
+    struct Form {
+        csrf_token: Option<String>,
+    }
+    let (parts, body) = req.into_parts();
+    let body = body.compat().try_concat().await?;
+    let form: Form = serde_urlencoded::from_bytes(&body)
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid form: {}", e)))?;
+    match form.csrf_token {
+        Some(token) if crypto::constant_time_eq(&token, expected) => {
+            Ok(Request::from_parts(parts, Body::from(body)))
+        }
+        _ => Err(ErrorKind::Forbidden("missing or invalid csrf token".into()))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_auth() -> Auth {
+        Auth {
+            user_token: "jane-id".into(),
+            user_name: "jane".into(),
+            scopes: Scope::DEFAULT.to_vec(),
+            key_id: None,
+            csrf_token: Some("csrf".into()),
+            orgs: vec![],
+            org: None,
+            personal: true,
+            rate_limited: false,
+        }
+    }
+
+    fn check(method: Method, headers: &[(&str, &str)], body: &str, auth: &Auth) -> Result<String> {
+        let mut req = Request::builder();
+        req.method(method);
+        for (k, v) in headers {
+            req.header(*k, *v);
+        }
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        futures::executor::block_on(async {
+            let req = check_csrf(req, auth).await?;
+            let body = req.into_body().compat().try_concat().await?;
+            Ok(String::from_utf8(body.to_vec()).unwrap())
+        })
+    }
+
+    #[test]
+    fn changes_with_a_session_need_its_csrf_token() {
+        let auth = session_auth();
+        let form = &[("content-type", "application/x-www-form-urlencoded")];
+        assert!(check(Method::GET, &[], "", &auth).is_ok());
+        assert!(check(Method::POST, &[(CSRF_HEADER, "csrf")], "", &auth).is_ok());
+        assert_eq!(
+            check(Method::POST, &[(CSRF_HEADER, "forged")], "", &auth)
+                .err()
+                .unwrap()
+                .status(),
+            hyper::StatusCode::FORBIDDEN
+        );
+        assert!(check(Method::POST, &[], "", &auth).is_err());
+        assert!(check(Method::POST, form, "description=x", &auth).is_err());
+        // the form is still readable by the handler
+        assert_eq!(
+            check(Method::POST, form, "description=x&csrf_token=csrf", &auth).unwrap(),
+            "description=x&csrf_token=csrf"
+        );
+    }
+
+    #[test]
+    fn sign_in_attempts_are_limited_per_name_and_address() {
+        let now = chrono::Utc::now();
+        let counters = login_counters("jane", Some("203.0.113.7"), now);
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters[0].1, LOGIN_ATTEMPTS_PER_NAME);
+        assert_eq!(counters[1].1, LOGIN_ATTEMPTS_PER_ADDRESS);
+        assert!(counters[1].0.contains("203.0.113.7"));
+        // guesses at other names from the same address share its counter
+        let other = login_counters("joe", Some("203.0.113.7"), now);
+        assert_ne!(counters[0].0, other[0].0);
+        assert_eq!(counters[1].0, other[1].0);
+        assert_eq!(login_counters("jane", None, now), counters[..1].to_vec());
+    }
+
+    #[test]
+    fn api_keys_dont_need_csrf_tokens() {
+        let auth = Auth {
+            csrf_token: None,
+            ..session_auth()
+        };
+        assert!(check(Method::POST, &[], "", &auth).is_ok());
+    }
+}
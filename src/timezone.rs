@@ -0,0 +1,114 @@
+//! Timestamps are stored in utc and rendered in whatever timezone the
+//! requesting client asks for, either with a `tz` query parameter or the
+//! `x-mpix-timezone` header, e.g. `?tz=America/New_York`.
+//!
+//! Timestamp fields opt in to rendering with
+//! `#[serde(serialize_with = "timezone::serialize")]`, which renders in utc
+//! unless the value is being serialized through `to_json`.
+use crate::error::{ErrorKind, Result};
+use {
+    chrono::{DateTime, Utc},
+    chrono_tz::Tz,
+    hyper::{Body, Request},
+    serde::{Deserialize, Serialize, Serializer},
+    std::cell::Cell,
+};
+
+/// Header a client can pick its display timezone with
+pub const TZ_HEADER: &str = "x-mpix-timezone";
+
+thread_local! {
+    // only set for the duration of a synchronous `to_json` call
+    static RENDER_TZ: Cell<Tz> = const { Cell::new(Tz::UTC) };
+}
+
+pub fn parse(name: &str) -> Result<Tz> {
+    Ok(name
+        .parse::<Tz>()
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid timezone `{}`: {}", name, e)))?)
+}
+
+/// The timezone a request wants timestamps rendered in, utc by default
+pub fn from_request(request: &Request<Body>) -> Result<Tz> {
+    #[derive(Deserialize)]
+    struct Params {
+        tz: Option<String>,
+    }
+    let params: Params = serde_urlencoded::from_str(request.uri().query().unwrap_or(""))
+        .map_err(|e| ErrorKind::BadRequest(format!("Invalid query parameters: {}", e)))?;
+    let header = request
+        .headers()
+        .get(TZ_HEADER)
+        .map(|hv| hv.to_str())
+        .transpose()?;
+    match params.tz.as_deref().or(header) {
+        Some(name) => parse(name.trim()),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Serialize `value` as json with its timestamps rendered in `tz`
+pub fn to_json<T: Serialize>(value: &T, tz: Tz) -> Result<String> {
+    RENDER_TZ.with(|render_tz| {
+        let prev = render_tz.replace(tz);
+        let json = serde_json::to_string(value);
+        render_tz.set(prev);
+        Ok(json?)
+    })
+}
+
+/// Render a timestamp in the timezone set by `to_json`
+pub fn serialize<S: Serializer>(ts: &DateTime<Utc>, s: S) -> std::result::Result<S::Ok, S::Error> {
+    let tz = RENDER_TZ.with(Cell::get);
+    s.serialize_str(&ts.with_timezone(&tz).to_rfc3339())
+}
+
+/// `serialize` for optional timestamps
+pub fn serialize_opt<S: Serializer>(
+    ts: &Option<DateTime<Utc>>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match ts {
+        Some(ts) => serialize(ts, s),
+        None => s.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Stamped {
+        #[serde(serialize_with = "serialize")]
+        created: DateTime<Utc>,
+    }
+
+    #[test]
+    fn legacy_local_offsets_are_read_as_utc() {
+        let legacy: Stamped =
+            serde_json::from_str(r#"{"created":"2023-11-14T17:13:20-05:00"}"#).unwrap();
+        assert_eq!(legacy.created.timestamp(), 1_700_000_000);
+        assert_eq!(
+            serde_json::to_string(&legacy).unwrap(),
+            r#"{"created":"2023-11-14T22:13:20+00:00"}"#
+        );
+    }
+
+    #[test]
+    fn renders_in_requested_timezone() {
+        let stamped: Stamped =
+            serde_json::from_str(r#"{"created":"2023-11-14T22:13:20Z"}"#).unwrap();
+        let tz = parse("America/New_York").unwrap();
+        assert_eq!(
+            to_json(&stamped, tz).unwrap(),
+            r#"{"created":"2023-11-14T17:13:20-05:00"}"#
+        );
+        // rendering doesn't leak into later serialization
+        assert_eq!(
+            serde_json::to_string(&stamped).unwrap(),
+            r#"{"created":"2023-11-14T22:13:20+00:00"}"#
+        );
+        assert!(parse("Mars/Olympus_Mons").is_err());
+    }
+}